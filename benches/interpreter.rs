@@ -0,0 +1,41 @@
+use {
+    std::{
+        fs,
+        path::Path,
+        process::{
+            Command,
+            Stdio,
+        },
+    },
+    criterion::{
+        Criterion,
+        criterion_group,
+        criterion_main,
+    },
+};
+
+/// Runs each Lox script in this directory with the release interpreter.
+fn interpreter(c: &mut Criterion) {
+    let mut group = c.benchmark_group("interpreter");
+    group.sample_size(10);
+    let mut scripts = fs::read_dir(Path::new(env!("CARGO_MANIFEST_DIR")).join("benches")).expect("failed to read benches directory")
+        .map(|entry| entry.expect("failed to read benches directory").path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "lox"))
+        .collect::<Vec<_>>();
+    scripts.sort();
+    for script in scripts {
+        let name = script.file_stem().expect("script has no file name").to_string_lossy().into_owned();
+        group.bench_function(name, |b| b.iter(|| {
+            let status = Command::new(env!("CARGO_BIN_EXE_rlox"))
+                .arg(&script)
+                .stdout(Stdio::null())
+                .status()
+                .expect("failed to run rlox");
+            assert!(status.success(), "{} failed", script.display());
+        }));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, interpreter);
+criterion_main!(benches);
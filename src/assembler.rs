@@ -0,0 +1,547 @@
+//! Turns the text format printed by `--disassemble` back into bytecode.
+//!
+//! Each function starts with a `== name ==` header (`<script>` for top-level code), optionally followed by an `arity N` line. The functions defined in a function follow it in the order of their constant indices. Instructions are written as `[offset] [position] OpCode [operand]`:
+//!
+//! * The offset column is optional, but can only be given together with the position column, since a single number before the opcode is read as the position. If present, it acts as a label for the instruction, so jump targets printed by the disassembler keep working when instructions are inserted or removed. Other labels can be defined on their own line as `name:`.
+//! * The position column is optional. It's written as `line:column`, or as just the line if the column is unknown. `|` or an omitted position means the same position as the previous instruction.
+//! * Constant operands are written as `0x03 (value)`. The index or the value can be omitted, in which case the value is declared elsewhere or added as a new constant, respectively. Values are written as `nil`, `true`, `false`, numbers, `"strings"`, `<fn name>`, `<closure name>`, or `<native fn name>`. Constants which aren't used by any instruction can be declared as `constant 0x03 (value)`.
+//! * Global slot operands are written as `0x03 (name)`. The slot index can be omitted, in which case the slot already used for that name or a new one is used. The top-level script lists all global slots as `global 0x03 (name)`, including those of native functions.
+//! * Jump operands are written as `(-> label)`, optionally preceded by the raw offset, which is ignored if a label is given.
+//! * Constant indices and local slots above `0xff` and jump offsets above `0xffff` need a `Wide` prefix before the opcode, e.g. `Wide Constant 0x0100 (1)`.
+//!
+//! Everything after a `;` is a comment.
+
+use {
+    std::{
+        collections::HashMap,
+        convert::TryFrom,
+    },
+    gc::Gc,
+    crate::{
+        error::{
+            Error,
+            Result,
+        },
+        intern::{
+            InternedString,
+            Interner,
+        },
+        span::Position,
+        value::{
+            Closure,
+            FunctionInner,
+            Value,
+        },
+        vm::OpCode,
+    },
+};
+
+macro_rules! error {
+    ($line:expr, $($arg:tt)*) => {{
+        return Err(Error::Assemble {
+            msg: format!($($arg)*),
+            line: $line,
+        })
+    }};
+}
+
+enum Token<'a> {
+    Word(&'a str),
+    /// The contents of a pair of parentheses.
+    Group(&'a str),
+}
+
+fn tokenize(line_no: u32, line: &str) -> Result<Vec<Token<'_>>> {
+    let mut tokens = Vec::default();
+    let mut rest = line;
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() || rest.starts_with(';') { break }
+        if let Some(group) = rest.strip_prefix('(') {
+            let mut in_string = false;
+            let mut escaped = false;
+            let end = group.char_indices().find(|&(_, c)| {
+                if escaped {
+                    escaped = false;
+                } else if in_string {
+                    match c {
+                        '\\' => escaped = true,
+                        '"' => in_string = false,
+                        _ => {}
+                    }
+                } else {
+                    match c {
+                        '"' => in_string = true,
+                        ')' => return true,
+                        _ => {}
+                    }
+                }
+                false
+            });
+            let (end, _) = if let Some(end) = end { end } else { error!(line_no, "unclosed parenthesis") };
+            tokens.push(Token::Group(group[..end].trim()));
+            rest = &group[end + 1..];
+        } else {
+            let end = rest.find(|c: char| c.is_whitespace() || c == '(' || c == ';').unwrap_or(rest.len());
+            tokens.push(Token::Word(&rest[..end]));
+            rest = &rest[end..];
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_int<T: TryFrom<u64>>(line_no: u32, word: &str) -> Result<T> {
+    let n = if let Some(hex) = word.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16)
+    } else {
+        word.parse()
+    };
+    match n.ok().and_then(|n| T::try_from(n).ok()) {
+        Some(n) => Ok(n),
+        None => error!(line_no, "invalid or out of range number: {}", word),
+    }
+}
+
+fn parse_string(line_no: u32, literal: &str) -> Result<String> {
+    let inner = match literal.strip_prefix('"').and_then(|literal| literal.strip_suffix('"')) {
+        Some(inner) => inner,
+        None => error!(line_no, "invalid string literal: {}", literal),
+    };
+    let mut s = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            s.push(c);
+            continue
+        }
+        s.push(match chars.next() {
+            Some('0') => '\0',
+            Some('n') => '\n',
+            Some('r') => '\r',
+            Some('t') => '\t',
+            Some(c @ '\\') | Some(c @ '"') | Some(c @ '\'') => c,
+            Some('u') => {
+                let rest = chars.as_str();
+                let escape = rest.strip_prefix('{').and_then(|rest| rest.find('}').map(|end| &rest[..end]));
+                match escape.and_then(|hex| u32::from_str_radix(hex, 16).ok()).and_then(std::char::from_u32) {
+                    Some(c) => {
+                        chars = rest[escape.expect("checked above").len() + 2..].chars();
+                        c
+                    }
+                    None => error!(line_no, "invalid unicode escape in string literal"),
+                }
+            }
+            _ => error!(line_no, "invalid escape in string literal"),
+        });
+    }
+    Ok(s)
+}
+
+/// A constant which refers to a function defined in a later section.
+struct PendingFunction {
+    const_idx: usize,
+    name: String,
+    is_closure: bool,
+    line_no: u32,
+}
+
+struct Jump {
+    operand_idx: usize,
+    target: String,
+    is_loop: bool,
+    wide: bool,
+    line_no: u32,
+}
+
+/// The state of assembling a single function.
+struct FunctionAssembler<'a> {
+    function: FunctionInner,
+    constants: Vec<Option<Value>>,
+    constant_literals: HashMap<usize, &'a str>,
+    pending_functions: Vec<PendingFunction>,
+    strings: &'a Interner,
+}
+
+impl<'a> FunctionAssembler<'a> {
+    /// Makes sure the constant table is large enough for the given index.
+    fn reserve_constant(&mut self, line_no: u32, const_idx: usize) -> Result {
+        if u32::try_from(const_idx).is_err() { error!(line_no, "constant index 0x{:x} out of range", const_idx) }
+        if self.constants.len() <= const_idx { self.constants.resize(const_idx + 1, None) }
+        Ok(())
+    }
+
+    /// Declares a constant at the given index, or at a new index if none is given.
+    fn constant(&mut self, line_no: u32, const_idx: Option<usize>, literal: &'a str) -> Result<usize> {
+        let const_idx = const_idx.unwrap_or(self.constants.len());
+        self.reserve_constant(line_no, const_idx)?;
+        if let Some(&prev_literal) = self.constant_literals.get(&const_idx) {
+            if prev_literal != literal { error!(line_no, "constant 0x{:02x} was already declared as ({})", const_idx, prev_literal) }
+        } else {
+            self.constant_literals.insert(const_idx, literal);
+            let value = match literal {
+                "nil" => Value::Nil,
+                "true" => Value::Bool(true),
+                "false" => Value::Bool(false),
+                _ if literal.starts_with('"') => Value::String(self.strings.intern(parse_string(line_no, literal)?)),
+                _ if literal.starts_with('<') && literal.ends_with('>') => {
+                    let inner = &literal[1..literal.len() - 1];
+                    if let Some(name) = inner.strip_prefix("native fn ") {
+                        match crate::native::by_name(name) {
+                            Some(native) => native.into(),
+                            None => error!(line_no, "unknown native function: {}", name),
+                        }
+                    } else {
+                        let (is_closure, name) = if let Some(name) = inner.strip_prefix("fn ") {
+                            (false, name)
+                        } else if let Some(name) = inner.strip_prefix("closure ") {
+                            (true, name)
+                        } else {
+                            error!(line_no, "invalid constant: ({})", literal)
+                        };
+                        // the function is defined in a later section, so it's filled in after this function is done
+                        self.pending_functions.push(PendingFunction { const_idx, name: name.to_owned(), is_closure, line_no });
+                        return Ok(const_idx)
+                    }
+                }
+                _ => match literal.parse() {
+                    Ok(n) => Value::Number(n),
+                    Err(_) => error!(line_no, "invalid constant: ({})", literal),
+                },
+            };
+            self.constants[const_idx] = Some(value);
+        }
+        Ok(const_idx)
+    }
+}
+
+struct Assembler<'a> {
+    lines: Vec<(u32, &'a str)>,
+    pos: usize,
+    globals: Vec<Option<&'a str>>,
+    strings: &'a Interner,
+}
+
+impl<'a> Assembler<'a> {
+    /// Declares a global slot with the given name, or returns the slot of that name if no slot is given.
+    fn global(&mut self, line_no: u32, slot: Option<usize>, name: &'a str) -> Result<usize> {
+        let existing = self.globals.iter().position(|&iter_name| iter_name == Some(name));
+        let slot = match (slot, existing) {
+            (Some(slot), Some(existing)) if slot != existing => error!(line_no, "global {} was already declared as 0x{:02x}", name, existing),
+            (Some(slot), _) => slot,
+            (None, Some(existing)) => existing,
+            (None, None) => self.globals.len(),
+        };
+        if u32::try_from(slot).is_err() { error!(line_no, "global slot 0x{:x} out of range", slot) }
+        if self.globals.len() <= slot { self.globals.resize(slot + 1, None) }
+        match self.globals[slot] {
+            Some(prev_name) if prev_name != name => error!(line_no, "global 0x{:02x} was already declared as ({})", slot, prev_name),
+            _ => self.globals[slot] = Some(name),
+        }
+        Ok(slot)
+    }
+
+    fn function(&mut self) -> Result<FunctionInner> {
+        let (header_line_no, header) = self.lines[self.pos];
+        self.pos += 1;
+        let name = match header.strip_prefix("==").and_then(|header| header.strip_suffix("==")).map(str::trim) {
+            Some("<script>") => None,
+            Some(name) if !name.is_empty() => Some(name),
+            _ => error!(header_line_no, "expected function header like `== name ==`"),
+        };
+        let mut function = FunctionInner::default(); // can't use struct update syntax since the Trace derive implements Drop
+        function.name = name.map(|name| Gc::new(name.to_owned()));
+        let mut asm = FunctionAssembler {
+            function,
+            constants: Vec::default(),
+            constant_literals: HashMap::default(),
+            pending_functions: Vec::default(),
+            strings: self.strings,
+        };
+        let mut labels = HashMap::<String, usize>::default();
+        let mut jumps = Vec::default();
+        let mut line = Position::default();
+        while let Some(&(line_no, text)) = self.lines.get(self.pos) {
+            if text.starts_with("==") { break }
+            self.pos += 1;
+            let tokens = tokenize(line_no, text)?;
+            match &*tokens {
+                [] => {}
+                [Token::Word("arity"), Token::Word(arity)] => asm.function.arity = parse_int(line_no, arity)?,
+                [Token::Word("constant"), Token::Word(const_idx), Token::Group(literal)] => { asm.constant(line_no, Some(parse_int(line_no, const_idx)?), literal)?; }
+                [Token::Word("global"), Token::Word(slot), Token::Group(name)] => { self.global(line_no, Some(parse_int(line_no, slot)?), name)?; }
+                [Token::Word(label)] if label.ends_with(':') => if labels.insert(label[..label.len() - 1].to_owned(), asm.function.chunk.len()).is_some() {
+                    error!(line_no, "duplicate label: {}", &label[..label.len() - 1])
+                },
+                _ => {
+                    let (mut opcode_idx, mut opcode) = match tokens.iter().enumerate().find_map(|(idx, token)| if let Token::Word(word) = token { OpCode::from_name(word).map(|opcode| (idx, opcode)) } else { None }) {
+                        Some(opcode) => opcode,
+                        None => error!(line_no, "expected an instruction"),
+                    };
+                    let line_token = match tokens[..opcode_idx] {
+                        [] => None,
+                        [Token::Word(line)] => Some(line),
+                        [Token::Word(offset), Token::Word(line)] => {
+                            if labels.insert(offset.to_owned(), asm.function.chunk.len()).is_some() { error!(line_no, "duplicate label: {}", offset) }
+                            Some(line)
+                        }
+                        _ => error!(line_no, "expected `[offset] [position] OpCode [operand]`"),
+                    };
+                    match line_token {
+                        None | Some("|") => {}
+                        Some(line_token) => line = if let Some((line_token, column_token)) = line_token.split_once(':') {
+                            Position { line: parse_int(line_no, line_token)?, column: parse_int(line_no, column_token)? }
+                        } else {
+                            Position { line: parse_int(line_no, line_token)?, column: 0 }
+                        },
+                    }
+                    let wide = if let OpCode::Wide = opcode {
+                        opcode_idx += 1;
+                        opcode = match tokens.get(opcode_idx) {
+                            Some(Token::Word(word)) => match OpCode::from_name(word) {
+                                Some(OpCode::Wide) | None => error!(line_no, "expected an instruction after the Wide prefix"),
+                                Some(opcode) => opcode,
+                            },
+                            _ => error!(line_no, "expected an instruction after the Wide prefix"),
+                        };
+                        asm.function.add_code(line, OpCode::Wide as u8);
+                        true
+                    } else {
+                        false
+                    };
+                    // writes an index operand, which is 4 bytes long with the Wide prefix
+                    macro_rules! index {
+                        ($index:expr) => {{
+                            let index: usize = $index;
+                            if wide {
+                                for byte in (index as u32).to_le_bytes().iter().copied() { asm.function.add_code(line, byte) }
+                            } else if let Ok(index) = u8::try_from(index) {
+                                asm.function.add_code(line, index);
+                            } else {
+                                error!(line_no, "operand 0x{:x} doesn't fit in one byte, add a Wide prefix", index)
+                            }
+                        }};
+                    }
+                    asm.function.add_code(line, opcode as u8);
+                    match (opcode, &tokens[opcode_idx + 1..]) {
+                        (OpCode::Add, [])
+                        | (OpCode::Div, [])
+                        | (OpCode::Echo, [])
+                        | (OpCode::Equal, [])
+                        | (OpCode::False, [])
+                        | (OpCode::Greater, [])
+                        | (OpCode::GreaterEqual, [])
+                        | (OpCode::Less, [])
+                        | (OpCode::LessEqual, [])
+                        | (OpCode::Mul, [])
+                        | (OpCode::Neg, [])
+                        | (OpCode::Nil, [])
+                        | (OpCode::Not, [])
+                        | (OpCode::Pop, [])
+                        | (OpCode::Print, [])
+                        | (OpCode::Return, [])
+                        | (OpCode::Sub, [])
+                        | (OpCode::True, []) if !wide => {}
+                        (OpCode::Call, [Token::Word(arg)]) if !wide => asm.function.add_code(line, parse_int(line_no, arg)?),
+                        (OpCode::GetLocal, [Token::Word(arg)])
+                        | (OpCode::SetLocal, [Token::Word(arg)]) => index!(parse_int::<u32>(line_no, arg)? as usize),
+                        (OpCode::DefineGlobal, operands)
+                        | (OpCode::GetGlobal, operands)
+                        | (OpCode::SetGlobal, operands) => {
+                            let slot = match operands {
+                                [Token::Group(name)] => self.global(line_no, None, name)?,
+                                [Token::Word(slot), Token::Group(name)] => self.global(line_no, Some(parse_int(line_no, slot)?), name)?,
+                                _ => error!(line_no, "expected a global operand like `0x00 (name)`"),
+                            };
+                            index!(slot);
+                        }
+                        (OpCode::Closure, operands)
+                        | (OpCode::Constant, operands) => {
+                            let const_idx = match operands {
+                                [Token::Word(const_idx)] => {
+                                    let const_idx = parse_int(line_no, const_idx)?;
+                                    asm.reserve_constant(line_no, const_idx)?;
+                                    const_idx
+                                }
+                                [Token::Group(literal)] => asm.constant(line_no, None, literal)?,
+                                [Token::Word(const_idx), Token::Group(literal)] => asm.constant(line_no, Some(parse_int(line_no, const_idx)?), literal)?,
+                                _ => error!(line_no, "expected a constant operand like `0x00 (value)`"),
+                            };
+                            index!(const_idx);
+                        }
+                        (OpCode::Jump, operands)
+                        | (OpCode::JumpIfFalsePeek, operands)
+                        | (OpCode::JumpIfFalsePop, operands)
+                        | (OpCode::JumpIfTruePeek, operands)
+                        | (OpCode::Loop, operands) => {
+                            let jump = match operands {
+                                [Token::Word(raw)] => parse_int::<u32>(line_no, raw)?,
+                                [Token::Group(target)] | [Token::Word(_), Token::Group(target)] => match target.strip_prefix("->") {
+                                    Some(target) => {
+                                        jumps.push(Jump { operand_idx: asm.function.chunk.len(), target: target.trim().to_owned(), is_loop: matches!(opcode, OpCode::Loop), wide, line_no });
+                                        0
+                                    }
+                                    None => error!(line_no, "expected a jump target like `(-> label)`"),
+                                },
+                                _ => error!(line_no, "expected a jump operand like `(-> label)`"),
+                            };
+                            if wide {
+                                for byte in jump.to_le_bytes().iter().copied() { asm.function.add_code(line, byte) }
+                            } else if let Ok(jump) = u16::try_from(jump) {
+                                for byte in jump.to_le_bytes().iter().copied() { asm.function.add_code(line, byte) }
+                            } else {
+                                error!(line_no, "jump offset 0x{:x} doesn't fit in two bytes, add a Wide prefix", jump)
+                            }
+                        }
+                        (_, _) => error!(line_no, "wrong operands for {}{:?}", if wide { "Wide " } else { "" }, opcode),
+                    }
+                }
+            }
+        }
+        let FunctionAssembler { mut function, mut constants, mut pending_functions, .. } = asm;
+        for Jump { operand_idx, target, is_loop, wide, line_no } in jumps {
+            let target_offset = match labels.get(&target) {
+                Some(&target_offset) => target_offset,
+                None => error!(line_no, "undefined label: {}", target),
+            };
+            let next = operand_idx + if wide { 4 } else { 2 };
+            let jump = if is_loop { next.checked_sub(target_offset) } else { target_offset.checked_sub(next) };
+            if wide {
+                match jump.and_then(|jump| u32::try_from(jump).ok()) {
+                    Some(jump) => { function.chunk.splice(operand_idx..next, jump.to_le_bytes().iter().copied()); }
+                    None => error!(line_no, "jump target {} is out of range", target),
+                }
+            } else {
+                match jump.and_then(|jump| u16::try_from(jump).ok()) {
+                    Some(jump) => { function.chunk.splice(operand_idx..next, jump.to_le_bytes().iter().copied()); }
+                    None => error!(line_no, "jump target {} is out of range, add a Wide prefix", target),
+                }
+            }
+        }
+        pending_functions.sort_by_key(|pending| pending.const_idx);
+        for PendingFunction { const_idx, name, is_closure, line_no } in pending_functions {
+            if self.pos >= self.lines.len() { error!(line_no, "missing definition of function {}", name) }
+            let nested = self.function()?;
+            if nested.name.as_ref().map_or(true, |nested_name| **nested_name != name) {
+                error!(line_no, "expected definition of function {} next, found {}", name, nested)
+            }
+            constants[const_idx] = Some(if is_closure {
+                Value::Closure(Closure::new(nested.wrap()))
+            } else {
+                Value::Function(nested.wrap())
+            });
+        }
+        for (const_idx, constant) in constants.into_iter().enumerate() {
+            match constant {
+                Some(constant) => { function.add_constant(constant); }
+                None => error!(header_line_no, "constant 0x{:02x} of function {} is never declared", const_idx, function),
+            }
+        }
+        Ok(function)
+    }
+}
+
+/// Assembles a script. Returns the script function and the names of its global slots, see `GlobalTable::link`.
+pub(crate) fn assemble(source: &str, strings: &Interner) -> Result<(FunctionInner, Vec<InternedString>)> {
+    let lines = source.lines()
+        .enumerate()
+        .map(|(idx, line)| ((idx + 1) as u32, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with(';'))
+        .collect::<Vec<_>>();
+    if lines.is_empty() { error!(1, "expected function header like `== <script> ==`") }
+    let mut assembler = Assembler { lines, pos: 0, globals: Vec::default(), strings };
+    let function = assembler.function()?;
+    if function.name.is_some() { error!(assembler.lines[0].0, "the first function must be <script>") }
+    if let Some(&(line_no, _)) = assembler.lines.get(assembler.pos) { error!(line_no, "unexpected function, functions must be referenced as constants from the function before them") }
+    let mut globals = Vec::with_capacity(assembler.globals.len());
+    for (slot, name) in assembler.globals.into_iter().enumerate() {
+        match name {
+            Some(name) => globals.push(strings.intern(name)),
+            None => error!(assembler.lines[0].0, "global 0x{:02x} is never declared", slot),
+        }
+    }
+    Ok((function, globals))
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        std::str,
+        crate::{
+            compiler,
+            intern::Interner,
+            vm::Vm,
+        },
+        super::assemble,
+    };
+
+    /// Compiles a script, disassembles it, assembles the disassembly, and checks that both functions have the same bytecode.
+    fn round_trip(source: &str) {
+        let mut vm = Vm::new();
+        let stmts = crate::parse(source.as_bytes()).unwrap_or_else(|e| panic!("failed to parse: {}", e));
+        let function = compiler::compile(stmts, &mut vm.global_table, &vm.strings).unwrap_or_else(|e| panic!("failed to compile: {}", e));
+        let mut compiled = Vec::default();
+        function.write_bytecode(vm.global_table.names(), &mut compiled).expect("failed to write compiled bytecode");
+        let mut disassembly = Vec::default();
+        function.write_disassembly(vm.global_table.names(), &mut disassembly).expect("failed to disassemble");
+        let disassembly = str::from_utf8(&disassembly).expect("disassembly is not UTF-8");
+        let (assembled, names) = assemble(disassembly, &Interner::default()).unwrap_or_else(|e| panic!("failed to assemble: {}\n{}", e, disassembly));
+        let mut reassembled = Vec::default();
+        assembled.write_bytecode(&names, &mut reassembled).expect("failed to write assembled bytecode");
+        assert!(compiled == reassembled, "bytecode changed after round trip:\n{}", disassembly);
+    }
+
+    #[test]
+    fn nested_functions() {
+        round_trip("
+            fun outer(a, b) {
+                var c = a + b;
+                fun middle() {
+                    fun inner(d) { return c + d; }
+                    c = c * 2;
+                    return inner;
+                }
+                return middle;
+            }
+            print outer(1, 2)()(3);
+            fun noop() {}
+            print noop;
+        ");
+    }
+
+    #[test]
+    fn jumps() {
+        round_trip("
+            var i = 0;
+            while (i < 10) {
+                if (i == 3 or i == 5 and !false) print i; else print -i;
+                i = i + 1;
+            }
+            for (var j = 0; j < 3; j = j + 1) {
+                if (j > 1) { print \"big\"; }
+            }
+            print nil and true;
+        ");
+    }
+
+    #[test]
+    fn strings_with_escapes() {
+        // Lox has no escape sequences, but the disassembler escapes these characters and the assembler has to undo that
+        round_trip("print \"backslash \\ tab \t newline \n bell \u{7} done\"; print \"ünïcødé 🦀\"; print \"\";");
+    }
+
+    #[test]
+    fn wide_operands() {
+        let mut source = String::default();
+        // more than 256 constants
+        for i in 0..300 { source.push_str(&format!("print {};\n", i)); }
+        // more than 256 locals
+        source.push_str("{\n");
+        for i in 0..300 { source.push_str(&format!("var l{} = {};\n", i, i)); }
+        source.push_str("print l299;\n}\n");
+        // a jump over more than 0xffff bytes
+        source.push_str("if (true) {\n");
+        for _ in 0..20000 { source.push_str("print 299;\n"); }
+        source.push_str("}\n");
+        round_trip(&source);
+    }
+}
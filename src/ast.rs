@@ -1,53 +1,63 @@
+use crate::span::{
+    Location,
+    Span,
+};
+
 pub(crate) enum Stmt {
     //TODO class
     Fun {
         name: String,
-        name_line: u32,
-        params: Vec<(u32, String)>,
+        name_span: Span,
+        params: Vec<(Span, String)>,
         body: Vec<Stmt>,
-        last_line: u32,
+        span: Span,
     },
     Var {
         name: String,
-        name_line: u32,
+        name_span: Span,
         init: Option<Expr>,
-        last_line: u32,
+        span: Span,
     },
     Expr {
         expr: Expr,
-        last_line: u32,
+        span: Span,
     },
     If {
         cond: Expr,
-        right_paren_line: u32,
         then: Box<Stmt>,
         else_: Option<Box<Stmt>>,
+        span: Span,
     },
     Print {
         expr: Expr,
-        last_line: u32,
+        span: Span,
     },
     Return {
-        keyword_line: u32,
         expr: Option<Expr>,
-        last_line: u32,
+        span: Span,
     },
     While {
         cond: Expr,
-        right_paren_line: u32,
         body: Box<Stmt>,
+        span: Span,
     },
     Block {
         stmts: Vec<Stmt>,
-        last_line: u32,
+        span: Span,
     },
 }
 
 impl Stmt {
-    pub(crate) fn last_line(&self) -> u32 {
+    /// Desugars a `for` loop into a `while` loop. `cond_start` is where the condition is or would be.
+    pub(crate) fn for_loop(span: Span, init: Option<Stmt>, cond_start: Location, cond: Option<Expr>, incr: Option<Expr>, body: Stmt) -> Stmt {
+        let body = if let Some(incr) = incr { Stmt::Block { span: body.span(), stmts: vec![body, Stmt::Expr { span: incr.span(), expr: incr }] } } else { body };
+        let while_loop = Stmt::While { cond: cond.unwrap_or(Expr::True { span: Span::empty(cond_start) }), body: Box::new(body), span };
+        if let Some(init) = init { Stmt::Block { stmts: vec![init, while_loop], span } } else { while_loop }
+    }
+
+    pub(crate) fn span(&self) -> Span {
         match self {
-            Stmt::Fun { last_line, .. } | Stmt::Var { last_line, .. } | Stmt::Expr { last_line, .. } | Stmt::Print { last_line, .. } | Stmt::Return { last_line, .. } | Stmt::Block { last_line, .. } => *last_line,
-            Stmt::If { then: inner, else_: None, .. } | Stmt::If { else_: Some(inner), .. } | Stmt::While { body: inner, .. } => inner.last_line(),
+            Stmt::Fun { span, .. } | Stmt::Var { span, .. } | Stmt::Expr { span, .. } | Stmt::If { span, .. } | Stmt::Print { span, .. } | Stmt::Return { span, .. } | Stmt::While { span, .. } | Stmt::Block { span, .. } => *span,
         }
     }
 }
@@ -56,55 +66,58 @@ pub(crate) enum Expr {
     Assign {
         rcpt: Option<Box<Expr>>,
         name: String,
-        name_line: u32,
+        name_span: Span,
         value: Box<Expr>,
+        span: Span,
     },
     Binary {
         lhs: Box<Expr>,
         op: BinaryOp,
+        op_span: Span,
         rhs: Box<Expr>,
+        span: Span,
     },
     Unary {
         op: UnaryOp,
         inner: Box<Expr>,
+        span: Span,
     },
     Call {
         rcpt: Box<Expr>,
         args: Vec<Expr>,
-        last_line: u32,
+        right_paren: Span,
+        span: Span,
     },
     //TODO property
     True {
-        line: u32,
+        span: Span,
     },
     False {
-        line: u32,
+        span: Span,
     },
     Nil {
-        line: u32,
+        span: Span,
     },
     //TODO this
     Number {
         value: f64,
-        line: u32,
+        span: Span,
     },
     String {
         value: String,
-        last_line: u32,
+        span: Span,
     },
     Variable {
         name: String,
-        line: u32,
+        span: Span,
     },
     //TODO super
 }
 
 impl Expr {
-    pub(crate) fn last_line(&self) -> u32 {
+    pub(crate) fn span(&self) -> Span {
         match self {
-            Expr::True { line } | Expr::False { line } | Expr::Nil { line } | Expr::Number { line, .. } | Expr::Variable { line, .. } => *line,
-            Expr::Call { last_line, .. } | Expr::String { last_line, .. } => *last_line,
-            Expr::Assign { value: inner, .. } | Expr::Binary { rhs: inner, .. } | Expr::Unary { inner, .. } => inner.last_line(),
+            Expr::Assign { span, .. } | Expr::Binary { span, .. } | Expr::Unary { span, .. } | Expr::Call { span, .. } | Expr::True { span } | Expr::False { span } | Expr::Nil { span } | Expr::Number { span, .. } | Expr::String { span, .. } | Expr::Variable { span, .. } => *span,
         }
     }
 }
@@ -1,7 +1,11 @@
 use {
-    std::convert::{
-        TryFrom as _,
-        TryInto as _,
+    std::{
+        collections::HashMap,
+        convert::{
+            TryFrom as _,
+            TryInto as _,
+        },
+        mem,
     },
     gc::Gc,
     crate::{
@@ -10,11 +14,22 @@ use {
             Error,
             Result,
         },
+        intern::{
+            InternedString,
+            Interner,
+        },
+        span::{
+            Location,
+            Span,
+        },
         value::{
             FunctionInner,
             Value,
         },
-        vm::OpCode,
+        vm::{
+            GlobalTable,
+            OpCode,
+        },
     },
 };
 
@@ -34,206 +49,239 @@ struct Local {
 #[must_use]
 struct Jump(usize);
 
-struct Compiler {
+/// Identifies constants which can share a slot in the constant pool. Numbers are compared by their bits, so `0.0` and `-0.0` stay distinct.
+#[derive(PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Number(u64),
+    String(InternedString),
+}
+
+struct Compiler<'a> {
     function: FunctionInner,
+    globals: &'a mut GlobalTable,
+    strings: &'a Interner,
     fn_type: FunctionType,
     locals: Vec<Local>,
+    /// Indices of the number and string constants already in the pool.
+    constant_indices: HashMap<ConstantKey, u32>,
     scope_depth: usize,
+    /// Errors in statements compiled so far, so they can all be reported at once.
+    errors: Vec<Error>,
 }
 
-impl Compiler {
-    fn new(fn_type: FunctionType) -> Compiler {
+impl<'a> Compiler<'a> {
+    fn new(fn_type: FunctionType, globals: &'a mut GlobalTable, strings: &'a Interner) -> Compiler<'a> {
         Compiler {
             function: FunctionInner::default(),
+            globals,
+            strings,
             locals: vec![Local {
                 name: String::default(), //TODO use this for methods and initializers
                 depth: Some(0),
                 is_captured: false,
             }],
+            constant_indices: HashMap::default(),
             scope_depth: if let FunctionType::Script = fn_type { 0 } else { 1 },
             fn_type,
+            errors: Vec::default(),
+        }
+    }
+
+    /// Compiles a statement in a block, function body, or script. On error, the error is recorded and the remaining statements are still checked.
+    fn declaration(&mut self, stmt: Stmt) {
+        if let Err(e) = self.compile_stmt(stmt) {
+            self.errors.push(e);
+            // a variable whose initializer has an error is still declared for the following statements
+            for local in &mut self.locals {
+                if local.depth.is_none() { local.depth = Some(self.scope_depth) }
+            }
         }
     }
 
     fn compile_stmt(&mut self, stmt: Stmt) -> Result {
         match stmt {
-            Stmt::Var { name, name_line, init, last_line } => {
-                let global = self.declare_variable(name_line, name, false)?;
+            Stmt::Var { name, name_span, init, span } => {
+                let global = self.declare_variable(name_span, name, false)?;
                 if let Some(init) = init {
                     self.compile_expr(init)?;
                 } else {
-                    self.emit(name_line, OpCode::Nil);
+                    self.emit(name_span.start, OpCode::Nil);
                 }
-                self.define_variable(last_line, global);
-            }
-            Stmt::Expr { expr, last_line } => {
-                self.compile_expr(expr)?;
-                self.emit(last_line, OpCode::Pop);
+                self.define_variable(span.end, global);
             }
-            Stmt::Fun { name, name_line, params, body, last_line } => {
+            Stmt::Expr { expr, span } => self.expression_statement(expr, span, OpCode::Pop)?,
+            Stmt::Fun { name, name_span, params, body, span } => {
                 let arity = params.len().try_into().map_err(|_| Error::Compile {
                     msg: format!("Can't have more than 255 parameters."),
-                    line: params[255].0,
+                    span: params[255].0,
                 })?;
-                let global = self.declare_variable(name_line, name.clone(), true)?; //TODO wrap in Gc to avoid the clone?
-                let mut compiler = Compiler::new(FunctionType::Function);
+                let global = self.declare_variable(name_span, name.clone(), true)?; //TODO wrap in Gc to avoid the clone?
+                let mut compiler = Compiler::new(FunctionType::Function, self.globals, self.strings);
                 compiler.function.arity = arity;
                 compiler.function.name = Some(Gc::new(name));
-                for (line, param) in params {
-                    compiler.declare_variable(line, param, true)?;
+                for (param_span, param) in params {
+                    if let Err(e) = compiler.declare_variable(param_span, param, true) { compiler.errors.push(e) }
                 }
                 for stmt in body {
-                    compiler.compile_stmt(stmt)?;
+                    compiler.declaration(stmt);
                 }
-                self.emit_constant(last_line, OpCode::Closure, Value::new(compiler.finalize(last_line).wrap()))?;
-                self.define_variable(last_line, global);
+                self.errors.append(&mut compiler.errors);
+                let function = compiler.finalize(span.end);
+                self.emit_constant(span.end, OpCode::Closure, Value::new(function.wrap()))?;
+                self.define_variable(span.end, global);
             }
-            Stmt::If { cond, right_paren_line, then, else_: Some(else_), .. } => {
+            Stmt::If { cond, then, else_: Some(else_), .. } => {
+                let cond_end = cond.span().end;
                 self.compile_expr(cond)?;
-                let then_jump = self.emit_jump(right_paren_line, OpCode::JumpIfFalsePop);
-                let then_last_line = then.last_line();
+                let then_jump = self.emit_jump(cond_end, OpCode::JumpIfFalsePop);
+                let then_end = then.span().end;
                 self.compile_stmt(*then)?;
-                let else_jump = self.emit_jump(then_last_line, OpCode::Jump);
-                self.patch_jump(then_last_line, then_jump)?;
-                let else_last_line = else_.last_line();
+                let else_jump = self.emit_jump(then_end, OpCode::Jump);
+                self.patch_jump(then_end, then_jump)?;
+                let else_end = else_.span().end;
                 self.compile_stmt(*else_)?;
-                self.patch_jump(else_last_line, else_jump)?;
+                self.patch_jump(else_end, else_jump)?;
             }
-            Stmt::If { cond, right_paren_line, then, else_: None, .. } => {
+            Stmt::If { cond, then, else_: None, .. } => {
+                let cond_end = cond.span().end;
                 self.compile_expr(cond)?;
-                let then_jump = self.emit_jump(right_paren_line, OpCode::JumpIfFalsePop);
-                let then_last_line = then.last_line();
+                let then_jump = self.emit_jump(cond_end, OpCode::JumpIfFalsePop);
+                let then_end = then.span().end;
                 self.compile_stmt(*then)?;
-                self.patch_jump(then_last_line, then_jump)?;
+                self.patch_jump(then_end, then_jump)?;
             }
-            Stmt::Print { expr, last_line } => {
+            Stmt::Print { expr, span } => {
                 self.compile_expr(expr)?;
-                self.emit(last_line, OpCode::Print);
+                self.emit(span.end, OpCode::Print);
             }
-            Stmt::Return { keyword_line, expr, last_line } => {
+            Stmt::Return { expr, span } => {
                 if let FunctionType::Script = self.fn_type {
                     return Err(Error::Compile {
                         msg: format!("Can't return from top-level code."),
-                        line: keyword_line,
+                        span: Span::empty(span.start),
                     })
                 }
                 if let Some(expr) = expr {
                     if let FunctionType::Initializer = self.fn_type {
                         return Err(Error::Compile {
                             msg: format!("Can't return a vale from an initializer."),
-                            line: keyword_line,
+                            span: Span::empty(span.start),
                         })
                     }
                     self.compile_expr(expr)?;
-                    self.emit(last_line, OpCode::Return);
+                    self.emit(span.end, OpCode::Return);
                 } else {
-                    self.emit_return(last_line);
+                    self.emit_return(span.end);
                 }
             }
-            Stmt::While { cond, right_paren_line, body, .. } => {
+            Stmt::While { cond, body, .. } => {
                 let loop_start = self.function.chunk.len();
+                let cond_end = cond.span().end;
                 self.compile_expr(cond)?;
-                let exit_jump = self.emit_jump(right_paren_line, OpCode::JumpIfFalsePop);
-                let body_last_line = body.last_line();
+                let exit_jump = self.emit_jump(cond_end, OpCode::JumpIfFalsePop);
+                let body_end = body.span().end;
                 self.compile_stmt(*body)?;
-                self.emit_loop(body_last_line, loop_start)?;
-                self.patch_jump(body_last_line, exit_jump)?;
+                self.emit_loop(body_end, loop_start)?;
+                self.patch_jump(body_end, exit_jump)?;
             }
-            Stmt::Block { stmts, last_line } => {
+            Stmt::Block { stmts, span } => {
                 self.begin_scope();
                 for stmt in stmts {
-                    self.compile_stmt(stmt)?;
+                    self.declaration(stmt);
                 }
-                self.end_scope(last_line);
+                self.end_scope(span.end);
             }
         }
         Ok(())
     }
 
+    /// Compiles an expression statement, using `opcode` to discard or print the value.
+    fn expression_statement(&mut self, expr: Expr, span: Span, opcode: OpCode) -> Result {
+        self.compile_expr(expr)?;
+        self.emit(span.end, opcode);
+        Ok(())
+    }
+
     fn compile_expr(&mut self, expr: Expr) -> Result {
         match expr {
             Expr::Assign { rcpt: Some(_), .. } => unimplemented!(), //TODO
-            Expr::Assign { rcpt: None, name, name_line, value } => {
-                let (arg, op) = if let Some(offset) = self.resolve_local(name_line, &name)? {
+            Expr::Assign { rcpt: None, name, name_span, value, .. } => {
+                let (arg, op) = if let Some(offset) = self.resolve_local(name_span, &name)? {
                     (offset, OpCode::SetLocal)
                 } else { //TODO upvalues
-                    (self.make_constant(name_line, Value::new(name))?, OpCode::SetGlobal)
+                    (self.global_slot(name_span, name)?, OpCode::SetGlobal)
                 };
-                let value_last_line = value.last_line();
                 self.compile_expr(*value)?;
-                self.emit_with_arg(value_last_line, op, arg);
+                self.emit_with_index(name_span.start, op, arg);
             }
-            Expr::Binary { lhs, op: BinaryOp::Or, rhs } => {
-                let lhs_last_line = lhs.last_line();
+            Expr::Binary { lhs, op: BinaryOp::Or, op_span, rhs, .. } => {
                 self.compile_expr(*lhs)?;
-                let jump = self.emit_jump(lhs_last_line, OpCode::JumpIfTruePeek);
-                self.emit(lhs_last_line, OpCode::Pop);
-                let rhs_last_line = rhs.last_line();
+                let jump = self.emit_jump(op_span.start, OpCode::JumpIfTruePeek);
+                self.emit(op_span.start, OpCode::Pop);
+                let rhs_end = rhs.span().end;
                 self.compile_expr(*rhs)?;
-                self.patch_jump(rhs_last_line, jump)?;
+                self.patch_jump(rhs_end, jump)?;
             }
-            Expr::Binary { lhs, op: BinaryOp::And, rhs } => {
-                let lhs_last_line = lhs.last_line();
+            Expr::Binary { lhs, op: BinaryOp::And, op_span, rhs, .. } => {
                 self.compile_expr(*lhs)?;
-                let jump = self.emit_jump(lhs_last_line, OpCode::JumpIfFalsePeek);
-                self.emit(lhs_last_line, OpCode::Pop);
-                let rhs_last_line = rhs.last_line();
+                let jump = self.emit_jump(op_span.start, OpCode::JumpIfFalsePeek);
+                self.emit(op_span.start, OpCode::Pop);
+                let rhs_end = rhs.span().end;
                 self.compile_expr(*rhs)?;
-                self.patch_jump(rhs_last_line, jump)?;
+                self.patch_jump(rhs_end, jump)?;
             }
-            Expr::Binary { lhs, op, rhs } => {
+            Expr::Binary { lhs, op, op_span, rhs, .. } => {
                 self.compile_expr(*lhs)?;
-                let rhs_last_line = rhs.last_line();
                 self.compile_expr(*rhs)?;
+                let location = op_span.start;
                 match op {
                     BinaryOp::Or => unreachable!(), // handled above
                     BinaryOp::And => unreachable!(), // handled above
                     BinaryOp::NotEqual => {
-                        self.emit(rhs_last_line, OpCode::Equal);
-                        self.emit(rhs_last_line, OpCode::Not);
+                        self.emit(location, OpCode::Equal);
+                        self.emit(location, OpCode::Not);
                     }
-                    BinaryOp::Equal => self.emit(rhs_last_line, OpCode::Equal),
-                    BinaryOp::Greater => self.emit(rhs_last_line, OpCode::Greater),
-                    BinaryOp::GreaterEqual => self.emit(rhs_last_line, OpCode::GreaterEqual),
-                    BinaryOp::Less => self.emit(rhs_last_line, OpCode::Less),
-                    BinaryOp::LessEqual => self.emit(rhs_last_line, OpCode::LessEqual),
-                    BinaryOp::Sub => self.emit(rhs_last_line, OpCode::Sub),
-                    BinaryOp::Add => self.emit(rhs_last_line, OpCode::Add),
-                    BinaryOp::Div => self.emit(rhs_last_line, OpCode::Div),
-                    BinaryOp::Mul => self.emit(rhs_last_line, OpCode::Mul),
+                    BinaryOp::Equal => self.emit(location, OpCode::Equal),
+                    BinaryOp::Greater => self.emit(location, OpCode::Greater),
+                    BinaryOp::GreaterEqual => self.emit(location, OpCode::GreaterEqual),
+                    BinaryOp::Less => self.emit(location, OpCode::Less),
+                    BinaryOp::LessEqual => self.emit(location, OpCode::LessEqual),
+                    BinaryOp::Sub => self.emit(location, OpCode::Sub),
+                    BinaryOp::Add => self.emit(location, OpCode::Add),
+                    BinaryOp::Div => self.emit(location, OpCode::Div),
+                    BinaryOp::Mul => self.emit(location, OpCode::Mul),
                 }
             }
-            Expr::Unary { op, inner } => {
-                let last_line = inner.last_line();
+            Expr::Unary { op, inner, span } => {
                 self.compile_expr(*inner)?;
-                self.emit(last_line, match op {
+                self.emit(span.start, match op {
                     UnaryOp::Not => OpCode::Not,
                     UnaryOp::Neg => OpCode::Neg,
                 });
             }
-            Expr::Call { rcpt, args, last_line } => {
+            Expr::Call { rcpt, args, right_paren, .. } => {
                 let arg_count = args.len().try_into().map_err(|_| Error::Compile {
                     msg: format!("Can't have more than 255 arguments."),
-                    line: args[255].last_line(),
+                    span: args[255].span(),
                 })?;
                 self.compile_expr(*rcpt)?;
                 for arg in args {
                     self.compile_expr(arg)?;
                 }
-                self.emit_with_arg(last_line, OpCode::Call, arg_count);
+                self.emit_with_arg(right_paren.start, OpCode::Call, arg_count);
             }
-            Expr::True { line } => self.emit(line, OpCode::True),
-            Expr::False { line } => self.emit(line, OpCode::False),
-            Expr::Nil { line } => self.emit(line, OpCode::Nil),
-            Expr::Number { value, line } => self.emit_constant(line, OpCode::Constant, Value::new(value))?,
-            Expr::String { value, last_line } => self.emit_constant(last_line, OpCode::Constant, Value::new(value))?,
-            Expr::Variable { name, line } => {
-                let (arg, op) = if let Some(offset) = self.resolve_local(line, &name)? {
+            Expr::True { span } => self.emit(span.start, OpCode::True),
+            Expr::False { span } => self.emit(span.start, OpCode::False),
+            Expr::Nil { span } => self.emit(span.start, OpCode::Nil),
+            Expr::Number { value, span } => self.emit_constant(span.start, OpCode::Constant, Value::new(value))?,
+            Expr::String { value, span } => self.emit_constant(span.start, OpCode::Constant, Value::String(self.strings.intern(value)))?,
+            Expr::Variable { name, span } => {
+                let (arg, op) = if let Some(offset) = self.resolve_local(span, &name)? {
                     (offset, OpCode::GetLocal)
                 } else { //TODO upvalues
-                    (self.make_constant(line, Value::new(name))?, OpCode::GetGlobal)
+                    (self.global_slot(span, name)?, OpCode::GetGlobal)
                 };
-                self.emit_with_arg(line, op, arg);
+                self.emit_with_index(span.start, op, arg);
             }
         }
         Ok(())
@@ -243,30 +291,30 @@ impl Compiler {
         self.scope_depth += 1;
     }
 
-    fn end_scope(&mut self, line: u32) {
+    fn end_scope(&mut self, location: Location) {
         self.scope_depth -= 1;
         while self.locals.last().map_or(false, |local| local.depth.expect("undefined local at end of scope") > self.scope_depth) {
-            self.emit(line, OpCode::Pop);
+            self.emit(location, OpCode::Pop);
             self.locals.pop();
         }
         //TODO close captured upvalues instead
     }
 
-    fn declare_variable(&mut self, name_line: u32, name: String, initialized: bool) -> Result<u8> {
+    fn declare_variable(&mut self, name_span: Span, name: String, initialized: bool) -> Result<u32> {
         if self.scope_depth > 0 {
             for local in self.locals.iter().rev() {
                 if local.depth.map_or(false, |depth| depth < self.scope_depth) { break }
                 if local.name == name {
                     return Err(Error::Compile {
                         msg: format!("Already variable with this name in this scope."),
-                        line: name_line,
+                        span: name_span,
                     })
                 }
             }
-            if self.locals.len() > u8::MAX.into() {
+            if u32::try_from(self.locals.len()).is_err() {
                 return Err(Error::Compile {
                     msg: format!("Too many local variables in function."),
-                    line: name_line,
+                    span: name_span,
                 })
             }
             self.locals.push(Local {
@@ -276,102 +324,151 @@ impl Compiler {
             });
             return Ok(0)
         }
-        //TODO intern variable name?
-        self.make_constant(name_line, Value::new(name))
+        self.global_slot(name_span, name)
     }
 
-    fn define_variable(&mut self, line: u32, global: u8) {
+    fn define_variable(&mut self, location: Location, global: u32) {
         if self.scope_depth > 0 {
             self.locals.last_mut().expect("no local to mark as initialized").depth = Some(self.scope_depth);
         } else {
-            self.emit_with_arg(line, OpCode::DefineGlobal, global);
+            self.emit_with_index(location, OpCode::DefineGlobal, global);
         }
     }
 
-    fn resolve_local(&self, line: u32, name: &str) -> Result<Option<u8>> {
+    fn resolve_local(&self, span: Span, name: &str) -> Result<Option<u32>> {
         Ok(if let Some((idx, local)) = self.locals.iter().enumerate().rfind(|(_, local)| local.name == name) {
             if local.depth.is_none() {
                 return Err(Error::Compile {
                     msg: format!("Can't read local variable in its own initializer."),
-                    line,
+                    span,
                 })
             }
-            Some(idx as u8)
+            Some(idx as u32) // the number of locals is checked in declare_variable
         } else {
             None
         })
     }
 
-    fn emit(&mut self, line: u32, opcode: OpCode) {
-        self.function.add_code(line, opcode as u8);
+    fn global_slot(&mut self, span: Span, name: String) -> Result<u32> {
+        self.globals.slot(self.strings.intern(name)).try_into().map_err(|_| Error::Compile {
+            msg: format!("Too many global variables."),
+            span,
+        })
     }
 
-    fn emit_with_arg(&mut self, line: u32, opcode: OpCode, arg: u8) {
-        self.emit(line, opcode);
-        self.function.add_code(line, arg);
+    fn emit(&mut self, location: Location, opcode: OpCode) {
+        self.function.add_code(location.into(), opcode as u8);
     }
 
-    fn emit_constant(&mut self, line: u32, opcode: OpCode, value: Gc<Value>) -> Result {
-        let const_idx = self.make_constant(line, value)?;
-        self.emit_with_arg(line, opcode, const_idx);
+    fn emit_with_arg(&mut self, location: Location, opcode: OpCode, arg: u8) {
+        self.emit(location, opcode);
+        self.function.add_code(location.into(), arg);
+    }
+
+    /// Emits an instruction with a constant index or local slot operand, using a `Wide` prefix if it doesn't fit in a byte.
+    fn emit_with_index(&mut self, location: Location, opcode: OpCode, index: u32) {
+        if let Ok(index) = u8::try_from(index) {
+            self.emit_with_arg(location, opcode, index);
+        } else {
+            self.emit(location, OpCode::Wide);
+            self.emit(location, opcode);
+            for byte in index.to_le_bytes().iter().copied() {
+                self.function.add_code(location.into(), byte);
+            }
+        }
+    }
+
+    fn emit_constant(&mut self, location: Location, opcode: OpCode, value: Value) -> Result {
+        let const_idx = self.make_constant(location, value)?;
+        self.emit_with_index(location, opcode, const_idx);
         Ok(())
     }
 
-    fn make_constant(&mut self, line: u32, value: Gc<Value>) -> Result<u8> {
-        self.function.add_constant(value).try_into().map_err(|_| Error::Compile {
+    fn make_constant(&mut self, location: Location, value: Value) -> Result<u32> {
+        let key = match value {
+            Value::Number(n) => Some(ConstantKey::Number(n.to_bits())),
+            Value::String(ref s) => Some(ConstantKey::String(s.clone())),
+            _ => None, // functions are never shared
+        };
+        if let Some(&const_idx) = key.as_ref().and_then(|key| self.constant_indices.get(key)) { return Ok(const_idx) }
+        let const_idx = self.function.add_constant(value).try_into().map_err(|_| Error::Compile {
             msg: format!("Too many constants in one chunk."),
-            line,
-        })
+            span: Span::empty(location),
+        })?;
+        if let Some(key) = key { self.constant_indices.insert(key, const_idx); }
+        Ok(const_idx)
     }
 
-    fn emit_jump(&mut self, line: u32, opcode: OpCode) -> Jump {
-        self.emit(line, opcode);
-        self.function.add_code(line, 0);
-        self.function.add_code(line, 0);
-        Jump(self.function.chunk.len() - 2)
+    /// Jumps are always emitted with a `Wide` prefix, since the offset isn't known yet. Those that don't need it are shrunk by `finalize`.
+    fn emit_jump(&mut self, location: Location, opcode: OpCode) -> Jump {
+        self.emit(location, OpCode::Wide);
+        self.emit(location, opcode);
+        for _ in 0..4 {
+            self.function.add_code(location.into(), 0);
+        }
+        Jump(self.function.chunk.len() - 4)
     }
 
-    fn patch_jump(&mut self, line: u32, Jump(from_idx): Jump) -> Result {
-        let offset = u16::try_from(self.function.chunk.len() - from_idx - 2).map_err(|_| Error::Compile {
+    fn patch_jump(&mut self, location: Location, Jump(from_idx): Jump) -> Result {
+        let offset = u32::try_from(self.function.chunk.len() - from_idx - 4).map_err(|_| Error::Compile {
             msg: format!("Too much code to jump over."),
-            line,
+            span: Span::empty(location),
         })?;
-        self.function.chunk.splice(from_idx..from_idx + 2, std::array::IntoIter::new(offset.to_le_bytes()));
+        self.function.chunk.splice(from_idx..from_idx + 4, offset.to_le_bytes().iter().copied());
         Ok(())
     }
 
-    fn emit_loop(&mut self, line: u32, loop_start: usize) -> Result {
-        self.emit(line, OpCode::Loop);
-        let offset = u16::try_from(self.function.chunk.len() - loop_start + 2).map_err(|_| Error::Compile {
+    fn emit_loop(&mut self, location: Location, loop_start: usize) -> Result {
+        self.emit(location, OpCode::Wide);
+        self.emit(location, OpCode::Loop);
+        let offset = u32::try_from(self.function.chunk.len() - loop_start + 4).map_err(|_| Error::Compile {
             msg: format!("Loop body too large."),
-            line,
+            span: Span::empty(location),
         })?;
-        let [b1, b2] = offset.to_le_bytes();
-        self.function.add_code(line, b1);
-        self.function.add_code(line, b2);
+        for byte in offset.to_le_bytes().iter().copied() {
+            self.function.add_code(location.into(), byte);
+        }
         Ok(())
     }
 
-    fn emit_return(&mut self, line: u32) {
+    fn emit_return(&mut self, location: Location) {
         if let FunctionType::Initializer = self.fn_type {
-            self.emit_with_arg(line, OpCode::GetLocal, 0);
+            self.emit_with_arg(location, OpCode::GetLocal, 0);
         } else {
-            self.emit(line, OpCode::Nil);
+            self.emit(location, OpCode::Nil);
         }
-        self.emit(line, OpCode::Return);
+        self.emit(location, OpCode::Return);
     }
 
-    fn finalize(mut self, line: u32) -> FunctionInner {
-        self.emit_return(line);
+    fn finalize(mut self, location: Location) -> FunctionInner {
+        self.emit_return(location);
+        self.function.reencode(|slot| slot); // shrinks jumps that don't need the Wide prefix
         self.function
     }
 }
 
-pub(crate) fn compile(body: Vec<Stmt>) -> Result<FunctionInner> {
-    let last_line = body.last().map_or(0, |stmt| stmt.last_line());
-    let mut compiler = Compiler::new(FunctionType::Script);
+/// Compiles a script. Global variables are assigned slots in the given table, and strings are interned in the given interner. Both must be the ones of the VM that runs the bytecode.
+pub(crate) fn compile(body: Vec<Stmt>, globals: &mut GlobalTable, strings: &Interner) -> Result<FunctionInner> {
+    compile_script(body, globals, strings, false)
+}
+
+/// Like `compile`, but top-level expression statements print their value unless it's `nil`.
+pub(crate) fn compile_repl(body: Vec<Stmt>, globals: &mut GlobalTable, strings: &Interner) -> Result<FunctionInner> {
+    compile_script(body, globals, strings, true)
+}
+
+fn compile_script(body: Vec<Stmt>, globals: &mut GlobalTable, strings: &Interner, echo: bool) -> Result<FunctionInner> {
+    let end = body.last().map_or_else(Location::default, |stmt| stmt.span().end);
+    let mut compiler = Compiler::new(FunctionType::Script, globals, strings);
     for stmt in body {
-        compiler.compile_stmt(stmt)?;
+        match stmt {
+            // only statements typed at the prompt are echoed, not those nested in blocks or loops
+            Stmt::Expr { expr, span } if echo => if let Err(e) = compiler.expression_statement(expr, span, OpCode::Echo) {
+                compiler.errors.push(e);
+            },
+            stmt => compiler.declaration(stmt),
+        }
     }
-    Ok(compiler.finalize(last_line))
+    Error::check(mem::take(&mut compiler.errors))?;
+    Ok(compiler.finalize(end))
 }
@@ -0,0 +1,388 @@
+//! Printing errors to stderr, either rustc-style with the offending source code, as JSON, or in the format expected by the craftinginterpreters test suite.
+
+use {
+    std::{
+        borrow::Cow,
+        convert::TryFrom as _,
+        fmt::Write as _,
+        process,
+        slice,
+        str::FromStr,
+    },
+    lalrpop_util::ParseError,
+    serde_json::json,
+    crate::{
+        error::Error,
+        resolver::Warning,
+        span::Position,
+        vm::CallFrame,
+    },
+};
+
+/// Tokens which are only expected because an expression could continue, summarized as “an operator” in hints.
+const OPERATORS: [&str; 12] = ["AND", "BANG_EQUAL", "EQUAL_EQUAL", "GREATER", "GREATER_EQUAL", "LESS", "LESS_EQUAL", "MINUS", "OR", "PLUS", "SLASH", "STAR"];
+
+#[derive(Clone, Copy)]
+pub(crate) enum ErrorFormat {
+    /// Shows the source code an error refers to, coloured if stderr is a terminal.
+    Human,
+    /// One JSON object per line, for tools that wrap rlox.
+    Json,
+    /// `[line N] Error: msg`, as checked by the craftinginterpreters test suite.
+    Plain,
+}
+
+impl FromStr for ErrorFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ErrorFormat, String> {
+        match s {
+            "human" => Ok(ErrorFormat::Human),
+            "json" => Ok(ErrorFormat::Json),
+            "plain" => Ok(ErrorFormat::Plain),
+            _ => Err(format!("unknown error format {:?}, expected human, json, or plain", s)),
+        }
+    }
+}
+
+/// A script that errors can point into.
+pub(crate) struct SourceFile {
+    pub(crate) name: String,
+    pub(crate) text: Vec<u8>,
+}
+
+impl SourceFile {
+    /// Returns the given line without its line break, or `None` if it doesn't exist or the file is bytecode.
+    fn line(&self, line: u32) -> Option<Cow<'_, str>> {
+        if self.text.first() == Some(&0xc0) { return None }
+        let line = self.text.split(|&byte| byte == b'\n').nth(usize::try_from(line).ok()?.checked_sub(1)?)?;
+        Some(String::from_utf8_lossy(line.strip_suffix(b"\r").unwrap_or(line)))
+    }
+}
+
+/// An error that ended the program, along with what's needed to print it.
+pub(crate) struct Report {
+    pub(crate) error: Error,
+    pub(crate) format: ErrorFormat,
+    pub(crate) source: Option<SourceFile>,
+}
+
+impl wheel::CustomExit for Report {
+    fn exit(self, _: &'static str) -> ! {
+        print(&self.error, self.format, self.source.as_ref());
+        process::exit(self.error.exit_code())
+    }
+}
+
+/// Prints an error to stderr. `source` is the script the error occurred in, if known.
+pub(crate) fn print(error: &Error, format: ErrorFormat, source: Option<&SourceFile>) {
+    let errors = if let Error::Multiple(errors) = error { &errors[..] } else { slice::from_ref(error) };
+    match format {
+        ErrorFormat::Human => {
+            let mut renderer = Renderer {
+                out: String::default(),
+                colors: atty::is(atty::Stream::Stderr),
+                source,
+            };
+            for (idx, error) in errors.iter().enumerate() {
+                if idx > 0 { let _ = writeln!(renderer.out); }
+                renderer.diagnostic(&Diagnostic::new(error));
+            }
+            if errors.len() > 1 {
+                let _ = writeln!(renderer.out);
+                renderer.header(Severity::Error, &format!("aborting due to {} previous errors", errors.len()));
+            }
+            eprint!("{}", renderer.out);
+        }
+        ErrorFormat::Json => for error in errors {
+            eprintln!("{}", json(&Diagnostic::new(error), source));
+        },
+        ErrorFormat::Plain => eprintln!("{}", error),
+    }
+}
+
+/// Prints the warnings found by the resolver to stderr. The plain format leaves them out, since the craftinginterpreters test suite fails on any unexpected output.
+pub(crate) fn print_warnings(warnings: &[Warning], format: ErrorFormat, source: Option<&SourceFile>) {
+    match format {
+        ErrorFormat::Human => {
+            let mut renderer = Renderer {
+                out: String::default(),
+                colors: atty::is(atty::Stream::Stderr),
+                source,
+            };
+            for warning in warnings {
+                renderer.diagnostic(&Diagnostic::warning(warning));
+                let _ = writeln!(renderer.out);
+            }
+            eprint!("{}", renderer.out);
+        }
+        ErrorFormat::Json => for warning in warnings {
+            eprintln!("{}", json(&Diagnostic::warning(warning), source));
+        },
+        ErrorFormat::Plain => {}
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Severity {
+    Error,
+    Warning,
+}
+
+impl Severity {
+    fn name(&self) -> &'static str {
+        match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        }
+    }
+
+    /// The ANSI style of the header and carets.
+    fn style(&self) -> &'static str {
+        match self {
+            Severity::Error => "1;31",
+            Severity::Warning => "1;33",
+        }
+    }
+}
+
+/// What the human and JSON formats show about an error or warning.
+struct Diagnostic<'a> {
+    severity: Severity,
+    /// The `Error` variant or lint name.
+    kind: &'static str,
+    message: Cow<'a, str>,
+    start: Option<Position>,
+    end: Option<Position>,
+    /// A short explanation shown next to the offending code.
+    hint: Option<String>,
+    /// Shown below the offending code in the human format.
+    note: Option<String>,
+    call_stack: &'a [CallFrame],
+}
+
+impl<'a> Diagnostic<'a> {
+    fn new(error: &'a Error) -> Diagnostic<'a> {
+        let mut diagnostic = Diagnostic {
+            severity: Severity::Error,
+            kind: error.kind(),
+            message: Cow::Owned(error.to_string()),
+            start: None,
+            end: None,
+            hint: None,
+            note: None,
+            call_stack: &[],
+        };
+        match error {
+            Error::Assemble { msg, line } => {
+                diagnostic.message = Cow::Borrowed(msg);
+                diagnostic.start = Some(Position { line: *line, column: 0 });
+            }
+            Error::Compile { msg, span } => {
+                diagnostic.message = Cow::Borrowed(msg);
+                diagnostic.start = Some(span.start.into());
+                diagnostic.end = Some(span.end.into());
+            }
+            Error::Interrupted { call_stack } => {
+                diagnostic.message = Cow::Borrowed("Interrupted.");
+                diagnostic.start = call_stack.last().map(CallFrame::position);
+                diagnostic.call_stack = call_stack;
+            }
+            Error::Parse(ParseError::User { error }) => return Diagnostic::new(error),
+            Error::Parse(ParseError::InvalidToken { location }) => {
+                diagnostic.message = Cow::Borrowed("invalid token");
+                diagnostic.start = Some((*location).into());
+            }
+            Error::Parse(ParseError::UnrecognizedEOF { location, expected }) => {
+                diagnostic.message = Cow::Borrowed("unexpected end of file");
+                diagnostic.start = Some((*location).into());
+                diagnostic.hint = expected_hint(expected);
+            }
+            Error::Parse(ParseError::UnrecognizedToken { token: (start, token, end), expected }) => {
+                diagnostic.message = Cow::Owned(format!("unexpected `{}`", token));
+                diagnostic.start = Some((*start).into());
+                diagnostic.end = Some((*end).into());
+                diagnostic.hint = expected_hint(expected);
+            }
+            Error::Parse(ParseError::ExtraToken { token: (start, token, end) }) => {
+                diagnostic.message = Cow::Owned(format!("unexpected `{}`", token));
+                diagnostic.start = Some((*start).into());
+                diagnostic.end = Some((*end).into());
+                diagnostic.hint = Some(format!("expected end of file"));
+            }
+            Error::Runtime { msg, call_stack } => {
+                diagnostic.message = Cow::Borrowed(msg);
+                diagnostic.start = call_stack.last().map(CallFrame::position);
+                diagnostic.call_stack = call_stack;
+            }
+            _ => {}
+        }
+        diagnostic
+    }
+
+    fn warning(warning: &'a Warning) -> Diagnostic<'a> {
+        Diagnostic {
+            severity: Severity::Warning,
+            kind: warning.lint.name(),
+            message: Cow::Borrowed(&warning.msg),
+            start: Some(warning.span.start.into()),
+            end: Some(warning.span.end.into()),
+            hint: None,
+            note: Some(format!("pass `-A {}` to hide warnings like this", warning.lint.name())),
+            call_stack: &[],
+        }
+    }
+}
+
+/// Formats an error or warning as a single-line JSON object, for editor plugins and CI.
+fn json(diagnostic: &Diagnostic<'_>, source: Option<&SourceFile>) -> serde_json::Value {
+    fn column(position: Position) -> Option<u32> {
+        if position.column == 0 { None } else { Some(position.column) }
+    }
+
+    fn position(position: Position) -> serde_json::Value {
+        json!({ "line": position.line, "column": column(position) })
+    }
+
+    let Diagnostic { severity, kind, message, start, end, hint, call_stack, .. } = diagnostic;
+    json!({
+        "severity": severity.name(),
+        "kind": kind,
+        "message": message,
+        "hint": hint,
+        "file": source.map(|source| &source.name),
+        "span": start.map(|start| json!({
+            "start": position(start),
+            "end": position(end.unwrap_or(start)),
+        })),
+        "call_stack": call_stack.iter().rev().map(|frame| {
+            let position = frame.position();
+            json!({
+                "function": frame.closure.function.borrow().name.as_ref().map(|name| name.to_string()),
+                "line": position.line,
+                "column": column(position),
+            })
+        }).collect::<Vec<_>>(),
+    })
+}
+
+struct Renderer<'a> {
+    out: String,
+    colors: bool,
+    source: Option<&'a SourceFile>,
+}
+
+impl Renderer<'_> {
+    /// Wraps `text` in the given ANSI style if colours are enabled.
+    fn paint<'t>(&self, style: &str, text: &'t str) -> Cow<'t, str> {
+        if self.colors {
+            Cow::Owned(format!("\x1b[{}m{}\x1b[0m", style, text))
+        } else {
+            Cow::Borrowed(text)
+        }
+    }
+
+    fn header(&mut self, severity: Severity, msg: &str) {
+        let _ = writeln!(self.out, "{}{}", self.paint(severity.style(), severity.name()), self.paint("1", &format!(": {}", msg)));
+    }
+
+    fn diagnostic(&mut self, diagnostic: &Diagnostic<'_>) {
+        self.header(diagnostic.severity, &diagnostic.message);
+        if let Some(start) = diagnostic.start {
+            self.snippet(diagnostic.severity, start, diagnostic.end, diagnostic.hint.as_deref());
+            let mut notes = self.call_stack(diagnostic.call_stack);
+            notes.extend(diagnostic.note.clone());
+            self.notes(start, &notes);
+        }
+    }
+
+    /// Prints where the problem is and the offending source line with `label` after the carets, which underline the code from `start` to `end`.
+    fn snippet(&mut self, severity: Severity, start: Position, end: Option<Position>, label: Option<&str>) {
+        let line = self.source.and_then(|source| source.line(start.line));
+        let gutter = " ".repeat(start.line.to_string().len());
+        let file = self.source.map_or_else(|| format!("line {}", start), |source| format!("{}:{}", source.name, start));
+        let _ = writeln!(self.out, "{}{} {}", gutter, self.paint("1;34", "-->"), file);
+        if let Some(line) = line {
+            let bar = self.paint("1;34", "|").into_owned();
+            let _ = writeln!(self.out, "{} {}", gutter, bar);
+            let _ = writeln!(self.out, "{} {} {}", self.paint("1;34", &start.line.to_string()), bar, line);
+            if start.column > 0 {
+                // keep tabs so the carets line up with the source line
+                let indent = line.chars().take(start.column as usize - 1).map(|c| if c == '\t' { '\t' } else { ' ' }).collect::<String>();
+                let len = match end {
+                    Some(end) if end.line == start.line => end.column.saturating_sub(start.column) as usize,
+                    Some(_) => line.chars().count().saturating_sub(start.column as usize - 1),
+                    None => 1,
+                }.max(1);
+                let carets = format!("{}{}", "^".repeat(len), label.map_or_else(String::default, |label| format!(" {}", label)));
+                let _ = writeln!(self.out, "{} {} {}{}", gutter, bar, indent, self.paint(severity.style(), &carets));
+            } else if let Some(label) = label {
+                let _ = writeln!(self.out, "{} {} {}", gutter, bar, self.paint(severity.style(), label));
+            }
+        } else if let Some(label) = label {
+            let _ = writeln!(self.out, "{} {} {}", gutter, self.paint("1;34", "="), label);
+        }
+    }
+
+    /// Lists the calls that led to a runtime error, if it didn't occur in top-level code.
+    fn call_stack(&self, call_stack: &[CallFrame]) -> Vec<String> {
+        if call_stack.len() <= 1 { return Vec::default() }
+        let mut frames = call_stack.iter().rev();
+        let mut notes = vec![format!("in {}", frame_name(frames.next().expect("checked above")))];
+        for frame in frames {
+            let position = frame.position();
+            notes.push(format!("called from {} at {}", frame_name(frame), self.source.map_or_else(|| format!("line {}", position), |source| format!("{}:{}", source.name, position))));
+        }
+        notes
+    }
+
+    /// Prints notes below the snippet for the code at `position`.
+    fn notes(&mut self, position: Position, notes: &[String]) {
+        if notes.is_empty() { return }
+        let gutter = " ".repeat(position.line.to_string().len());
+        let note_label = self.paint("1", "note").into_owned();
+        let _ = writeln!(self.out, "{} {}", gutter, self.paint("1;34", "|"));
+        for note in notes {
+            let _ = writeln!(self.out, "{} {} {}: {}", gutter, self.paint("1;34", "="), note_label, note);
+        }
+    }
+}
+
+fn frame_name(frame: &CallFrame) -> String {
+    frame.closure.function.borrow().name.as_ref().map_or_else(|| format!("script"), |name| format!("{}()", name))
+}
+
+/// Summarizes the tokens LALRPOP expected, e.g. as “expected an expression”.
+fn expected_hint(expected: &[String]) -> Option<String> {
+    let has = |name: &str| expected.iter().any(|token| token == name);
+    if expected.is_empty() { return None }
+    if has("PRINT") { return Some(format!("expected a statement")) }
+    if has("IDENTIFIER") && has("NUMBER") { return Some(format!("expected an expression")) }
+    let mut items = expected.iter().filter(|token| !OPERATORS.contains(&&***token)).map(|token| describe_token(token)).collect::<Vec<_>>();
+    if expected.iter().any(|token| OPERATORS.contains(&&**token)) { items.push(format!("an operator")) }
+    let last = items.pop()?;
+    Some(if items.is_empty() {
+        format!("expected {}", last)
+    } else {
+        format!("expected {} or {}", items.join(", "), last)
+    })
+}
+
+/// Turns a terminal name from the grammar into how it's written in source code.
+fn describe_token(name: &str) -> String {
+    match name {
+        "IDENTIFIER" => format!("an identifier"),
+        "NUMBER" => format!("a number"),
+        "STRING" => format!("a string"),
+        "LEFT_PAREN" => format!("`(`"),
+        "RIGHT_PAREN" => format!("`)`"),
+        "LEFT_BRACE" => format!("`{{`"),
+        "RIGHT_BRACE" => format!("`}}`"),
+        "SEMICOLON" => format!("`;`"),
+        "COMMA" => format!("`,`"),
+        "DOT" => format!("`.`"),
+        "BANG" => format!("`!`"),
+        "EQUAL" => format!("`=`"),
+        _ => format!("`{}`", name.to_lowercase()), // keywords
+    }
+}
@@ -2,81 +2,233 @@ use {
     std::{
         fmt,
         io,
-        num::ParseFloatError,
-        string::FromUtf8Error,
+        str::Utf8Error,
     },
     derive_more::From,
     lalrpop_util::ParseError,
+    rustyline::error::ReadlineError,
     crate::{
         lexer::Token,
+        span::{
+            Location,
+            Span,
+        },
+        value::FORMAT_VERSION,
         vm::CallFrame,
     },
 };
 
 #[derive(From)]
 pub enum Error {
-    Compile {
+    Assemble {
         msg: String,
         line: u32,
     },
+    BytecodeFeatures(u32),
+    BytecodeVersion {
+        format_version: Option<u16>,
+        rlox_version: Option<String>,
+    },
+    Checksum,
+    Compile {
+        msg: String,
+        span: Span,
+    },
     CompileRepl,
+    #[from]
+    CtrlC(ctrlc::Error),
     Decode(&'static str),
+    Interrupted {
+        call_stack: Vec<CallFrame>,
+    },
     #[from]
     Io(io::Error),
-    Parse(ParseError<u32, Token, Box<Error>>),
+    /// Several compile or parse errors, in the order they appear in the source code.
+    Multiple(Vec<Error>),
+    Parse(ParseError<Location, String, Box<Error>>),
     #[from]
-    ParseFloat(ParseFloatError),
+    Readline(ReadlineError),
     Runtime {
         msg: String,
         call_stack: Vec<CallFrame>,
     },
     #[from]
-    Utf8(FromUtf8Error),
+    Utf8(Utf8Error),
+    Verify {
+        function: String,
+        offset: usize,
+        msg: String,
+    },
 }
 
-impl From<ParseError<u32, Token, Error>> for Error {
-    fn from(e: ParseError<u32, Token, Error>) -> Error {
-        Error::Parse(e.map_error(Box::new))
+impl From<ParseError<Location, Token<'_>, Error>> for Error {
+    fn from(e: ParseError<Location, Token<'_>, Error>) -> Error {
+        Error::Parse(e.map_token(|token| token.to_string()).map_error(Box::new))
     }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Error::Compile { msg, line } => write!(f, "[line {}] Error: {}", line, msg),
-            Error::CompileRepl => write!(f, "invoking with --compile requires an input script"),
+            Error::Assemble { msg, line } => write!(f, "[line {}] Assembly error: {}", line, msg),
+            Error::BytecodeFeatures(flags) => write!(f, "bytecode uses features not supported by this version of rlox (flags 0x{:08x})", flags),
+            Error::BytecodeVersion { format_version: Some(format_version), rlox_version: Some(rlox_version) } => write!(f, "bytecode was written by rlox {} in format version {}, but rlox {} only reads format version {}; recompile the script from source", rlox_version, format_version, env!("CARGO_PKG_VERSION"), FORMAT_VERSION),
+            Error::BytecodeVersion { .. } => write!(f, "bytecode was written by an older version of rlox without a version header; recompile the script from source"),
+            Error::Checksum => write!(f, "bytecode checksum does not match, the file is corrupted"),
+            Error::Compile { msg, span } => write!(f, "[line {}] Error: {}", span.end.line, msg),
+            Error::CompileRepl => write!(f, "invoking with --compile or --assemble requires an input script"),
+            Error::CtrlC(e) => e.fmt(f),
             Error::Decode(ty) => write!(f, "invalid {} in bytecode", ty),
+            Error::Interrupted { call_stack } => {
+                writeln!(f, "Interrupted.")?;
+                fmt_call_stack(f, call_stack)
+            }
             Error::Io(e) => write!(f, "I/O error: {}", e),
+            Error::Multiple(errors) => {
+                for (idx, error) in errors.iter().enumerate() {
+                    if idx > 0 { writeln!(f)? }
+                    error.fmt(f)?;
+                }
+                Ok(())
+            }
             Error::Parse(ParseError::User { error }) => error.fmt(f),
-            Error::Parse(e) => write!(f, "parse error: {}", e),
-            Error::ParseFloat(e) => e.fmt(f),
+            Error::Parse(e) => {
+                // only show the lines of locations, like other errors
+                let e: ParseError<_, _, Error> = match e {
+                    ParseError::InvalidToken { location } => ParseError::InvalidToken { location: location.line },
+                    ParseError::UnrecognizedEOF { location, expected } => ParseError::UnrecognizedEOF { location: location.line, expected: expected.clone() },
+                    ParseError::UnrecognizedToken { token: (start, token, end), expected } => ParseError::UnrecognizedToken { token: (start.line, token.clone(), end.line), expected: expected.clone() },
+                    ParseError::ExtraToken { token: (start, token, end) } => ParseError::ExtraToken { token: (start.line, token.clone(), end.line) },
+                    ParseError::User { .. } => unreachable!(), // handled above
+                };
+                write!(f, "parse error: {}", e)
+            }
+            Error::Readline(e) => write!(f, "error reading input: {}", e),
             Error::Runtime { msg, call_stack } => {
                 writeln!(f, "{}", msg)?;
-                for frame in call_stack.into_iter().rev() {
-                    write!(f, "[line {}] in ", frame.closure.function.borrow().lines[frame.ip - 1])?;
-                    if let Some(ref name) = frame.closure.function.borrow().name {
-                        writeln!(f, "{}()", name)?;
-                    } else {
-                        writeln!(f, "script")?;
-                    }
-                }
-                Ok(())
+                fmt_call_stack(f, call_stack)
             }
             Error::Utf8(e) => write!(f, "error reading string: {}", e),
+            Error::Verify { function, offset, msg } => write!(f, "invalid bytecode in {} at offset 0x{:04x}: {}", function, offset, msg),
+        }
+    }
+}
+
+fn fmt_call_stack(f: &mut fmt::Formatter<'_>, call_stack: &[CallFrame]) -> fmt::Result {
+    for frame in call_stack.iter().rev() {
+        write!(f, "[line {}] in ", frame.position().line)?;
+        if let Some(ref name) = frame.closure.function.borrow().name {
+            writeln!(f, "{}()", name)?;
+        } else {
+            writeln!(f, "script")?;
         }
     }
+    Ok(())
 }
 
-impl wheel::CustomExit for Error {
-    fn exit(self, _: &'static str) -> ! {
-        eprintln!("{}", self);
-        std::process::exit(match self {
-            Error::Compile { .. } | Error::Parse(_) => 65,
+impl Error {
+    /// Returns `Err` if any errors were found in a script, combining them if there are several.
+    pub(crate) fn check(mut errors: Vec<Error>) -> Result {
+        errors.sort_by_key(Error::offset);
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.pop().expect("checked length")),
+            _ => Err(Error::Multiple(errors)),
+        }
+    }
+
+    /// Where in the source code the error occurred, for sorting.
+    fn offset(&self) -> usize {
+        match self {
+            Error::Compile { span, .. } => span.start.offset,
+            Error::Parse(ParseError::InvalidToken { location }) => location.offset,
+            Error::Parse(ParseError::UnrecognizedEOF { location, .. }) => location.offset,
+            Error::Parse(ParseError::UnrecognizedToken { token: (start, _, _), .. }) => start.offset,
+            Error::Parse(ParseError::ExtraToken { token: (start, _, _) }) => start.offset,
+            Error::Parse(ParseError::User { error }) => error.offset(),
+            _ => usize::MAX,
+        }
+    }
+
+    /// The name of the variant, identifying the kind of error in machine-readable output.
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            Error::Assemble { .. } => "Assemble",
+            Error::BytecodeFeatures(_) => "BytecodeFeatures",
+            Error::BytecodeVersion { .. } => "BytecodeVersion",
+            Error::Checksum => "Checksum",
+            Error::Compile { .. } => "Compile",
+            Error::CompileRepl => "CompileRepl",
+            Error::CtrlC(_) => "CtrlC",
+            Error::Decode(_) => "Decode",
+            Error::Interrupted { .. } => "Interrupted",
+            Error::Io(_) => "Io",
+            Error::Multiple(_) => "Multiple",
+            Error::Parse(ParseError::User { error }) => error.kind(),
+            Error::Parse(_) => "Parse",
+            Error::Readline(_) => "Readline",
+            Error::Runtime { .. } => "Runtime",
+            Error::Utf8(_) => "Utf8",
+            Error::Verify { .. } => "Verify",
+        }
+    }
+
+    pub(crate) fn exit_code(&self) -> i32 {
+        match self {
+            Error::Assemble { .. } | Error::Compile { .. } | Error::Parse(_) => 65,
             Error::Runtime { .. } => 70,
             Error::Io(_) => 74,
+            Error::Interrupted { .. } => 130,
+            Error::Multiple(errors) => errors.iter().map(Error::exit_code).max().unwrap_or(1),
             _ => 1,
-        })
+        }
     }
 }
 
 pub(crate) type Result<T = (), E = Error> = std::result::Result<T, E>;
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::{
+            resolver::Lints,
+            vm::Vm,
+        },
+        super::Error,
+    };
+
+    /// Compiles a script that has errors and returns all of them.
+    fn errors(source: &[u8]) -> Vec<Error> {
+        match crate::compile(source, &mut Vm::new(), &Lints::new(&[], &[])) {
+            Ok(_) => panic!("invalid script was accepted"),
+            Err(e) => {
+                assert_eq!(e.exit_code(), 65);
+                match e {
+                    Error::Multiple(errors) => errors,
+                    e => vec![e],
+                }
+            }
+        }
+    }
+
+    fn assert_sorted(errors: &[Error]) {
+        let offsets = errors.iter().map(Error::offset).collect::<Vec<_>>();
+        assert!(offsets.windows(2).all(|pair| pair[0] < pair[1]), "errors are not sorted: {:?}", offsets);
+    }
+
+    #[test]
+    fn all_syntax_errors() {
+        let errors = errors(b"print 1 +;\nvar = 5;\nprint \"\xff\";\nprint 0xg;\nfun f(a b) {}\nprint \"ok\";\n");
+        assert_eq!(errors.len(), 5);
+        assert_sorted(&errors);
+        assert!(matches!(errors[2], Error::Compile { ref msg, span } if msg == "Invalid UTF-8 in string." && span.start.line == 3));
+    }
+
+    #[test]
+    fn all_compile_errors() {
+        let errors = errors(b"{ var a = 1; var a = 2; }\n{ var b = b; }\nfun g(x, x) { return x; }\nreturn 1;\nprint \"ok\";\n");
+        assert_eq!(errors.len(), 4);
+        assert_sorted(&errors);
+        assert!(errors.iter().all(|e| matches!(e, Error::Compile { .. })));
+    }
+}
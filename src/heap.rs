@@ -0,0 +1,27 @@
+use std::sync::atomic::{
+    AtomicUsize,
+    Ordering::SeqCst,
+};
+
+static BYTES_ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+/// Accounts for the memory used by a heap object. The bytes are counted as allocated until this is dropped, i.e. until the object containing it is garbage collected.
+pub(crate) struct Allocation(usize);
+
+impl Allocation {
+    pub(crate) fn new(size: usize) -> Allocation {
+        BYTES_ALLOCATED.fetch_add(size, SeqCst);
+        Allocation(size)
+    }
+}
+
+impl Drop for Allocation {
+    fn drop(&mut self) {
+        BYTES_ALLOCATED.fetch_sub(self.0, SeqCst);
+    }
+}
+
+/// The number of bytes currently allocated for strings, closures, and other heap objects.
+pub(crate) fn bytes_allocated() -> usize {
+    BYTES_ALLOCATED.load(SeqCst)
+}
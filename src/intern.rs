@@ -0,0 +1,146 @@
+use {
+    std::{
+        cell::RefCell,
+        collections::HashMap,
+        fmt,
+        hash::{
+            Hash,
+            Hasher,
+        },
+        ops::Deref,
+        rc::{
+            Rc,
+            Weak,
+        },
+    },
+    crate::{
+        heap::Allocation,
+        value::Value,
+    },
+};
+
+type Table = RefCell<HashMap<Rc<str>, Weak<Inner>>>;
+
+/// The strings of one VM that are currently alive, so that equal strings share one allocation. Entries are removed when their string is dropped.
+///
+/// Cloning an interner gives a handle to the same table.
+#[derive(Clone, Default)]
+pub(crate) struct Interner(Rc<Table>);
+
+impl Interner {
+    /// Returns the string with the given contents, allocating it if it isn't in the table yet.
+    pub(crate) fn intern(&self, s: impl AsRef<str> + Into<Rc<str>>) -> InternedString {
+        let mut strings = self.0.borrow_mut();
+        if let Some(inner) = strings.get(s.as_ref()).and_then(Weak::upgrade) { return InternedString(inner) }
+        let s = s.into();
+        let inner = Rc::new(Inner {
+            _allocation: Allocation::new(Value::string_size(s.len())),
+            s: s.clone(),
+            table: Rc::downgrade(&self.0),
+        });
+        strings.insert(s, Rc::downgrade(&inner));
+        InternedString(inner)
+    }
+
+    /// Returns the string with the given contents if it's in the table, without allocating.
+    pub(crate) fn get(&self, s: &str) -> Option<InternedString> {
+        self.0.borrow().get(s).and_then(Weak::upgrade).map(InternedString)
+    }
+}
+
+struct Inner {
+    s: Rc<str>,
+    /// The table of the interner that created this string.
+    table: Weak<Table>,
+    _allocation: Allocation,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        // the table may already be gone if this outlives its VM
+        if let Some(table) = self.table.upgrade() {
+            if let Ok(mut strings) = table.try_borrow_mut() {
+                if strings.get(&self.s).map_or(false, |entry| entry.strong_count() == 0) {
+                    strings.remove(&self.s);
+                }
+            }
+        }
+    }
+}
+
+/// A string which is equal to another only if it's the same allocation. Strings with the same contents from the same `Interner` are always the same allocation, so equality and hashing only look at the pointer.
+#[derive(Clone)]
+pub(crate) struct InternedString(Rc<Inner>);
+
+impl Deref for InternedString {
+    type Target = str;
+
+    fn deref(&self) -> &str { &self.0.s }
+}
+
+impl PartialEq for InternedString {
+    fn eq(&self, rhs: &InternedString) -> bool {
+        Rc::ptr_eq(&self.0, &rhs.0)
+    }
+}
+
+impl Eq for InternedString {}
+
+impl Hash for InternedString {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Rc::as_ptr(&self.0).hash(state);
+    }
+}
+
+impl fmt::Debug for InternedString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+impl fmt::Display for InternedString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (**self).fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Interner;
+
+    #[test]
+    fn equal_strings_share_allocation() {
+        let strings = Interner::default();
+        assert!(strings.intern("foo") == strings.intern(String::from("foo")));
+        assert!(strings.intern("foo") != strings.intern("bar"));
+    }
+
+    #[test]
+    fn interners_are_separate() {
+        let a = Interner::default();
+        let b = Interner::default();
+        let foo = a.intern("foo");
+        assert!(b.get("foo").is_none());
+        assert!(foo != b.intern("foo"));
+    }
+
+    #[test]
+    fn get_does_not_insert() {
+        let strings = Interner::default();
+        assert!(strings.get("foo").is_none());
+        assert!(strings.0.borrow().is_empty());
+        let foo = strings.intern("foo");
+        assert!(strings.get("foo") == Some(foo));
+    }
+
+    #[test]
+    fn dropped_strings_are_removed() {
+        let strings = Interner::default();
+        drop(strings.intern("foo"));
+        assert!(strings.0.borrow().is_empty());
+        // strings may outlive their interner
+        let bar = strings.intern("bar");
+        drop(strings);
+        assert_eq!(&*bar, "bar");
+    }
+}
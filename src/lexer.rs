@@ -1,310 +1,487 @@
 use {
     std::{
         fmt,
-        io::{
-            self,
-            prelude::*,
-        },
+        str,
     },
-    byteorder::ReadBytesExt as _,
-    crate::error::{
-        Error,
-        Result,
+    crate::{
+        error::{
+            Error,
+            Result,
+        },
+        span::{
+            Location,
+            Span,
+        },
     },
 };
 pub(crate) use self::Token::*;
 
+pub(crate) const KEYWORDS: [&str; 16] = ["and", "class", "else", "false", "for", "fun", "if", "nil", "or", "print", "return", "super", "this", "true", "var", "while"];
+
+/// Splits a fully loaded source file into tokens. Identifiers and strings borrow from the source instead of being copied.
+///
+/// Invalid tokens are yielded as `ERROR` so the parser can recover from them. Their errors are collected in `errors`.
 pub(crate) struct Lexer<'a> {
-    peek: Option<u8>,
-    stream: Option<Box<dyn Read + 'a>>,
+    pub(crate) errors: Vec<Error>,
+    source: &'a [u8],
+    pos: usize,
     line: u32,
+    /// The offset of the first byte of the current line.
+    line_start: usize,
+    /// The column at `column_offset`, so columns don't have to be counted from the start of the line for every token.
+    column: u32,
+    column_offset: usize,
 }
 
 impl<'a> Lexer<'a> {
-    pub(crate) fn new(stream: Box<dyn Read + 'a>) -> Lexer {
+    pub(crate) fn new(source: &'a [u8]) -> Lexer<'a> {
         Lexer {
-            peek: None,
-            stream: Some(stream),
+            errors: Vec::default(),
+            source,
+            pos: 0,
             line: 1,
+            line_start: 0,
+            column: 1,
+            column_offset: 0,
         }
     }
 
-    fn next_byte(&mut self) -> Result<Option<u8>> {
-        if let Some(c) = self.peek.take() {
-            Ok(Some(c))
-        } else if let Some(ref mut stream) = self.stream {
-            match stream.read_u8() {
-                Ok(c) => Ok(Some(c)),
-                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
-                    self.stream = None;
-                    Ok(None)
-                }
-                Err(e) => Err(e.into()),
-            }
+    /// Consumes a newline character.
+    fn newline(&mut self) {
+        self.pos += 1;
+        self.line += 1;
+        self.line_start = self.pos;
+    }
+
+    /// Returns the current location.
+    fn location(&mut self) -> Location {
+        self.location_at(self.pos)
+    }
+
+    /// Returns the location of a byte on the current line. Must be called with nondecreasing offsets.
+    fn location_at(&mut self, offset: usize) -> Location {
+        if self.column_offset < self.line_start {
+            self.column = 1;
+            self.column_offset = self.line_start;
+        }
+        self.column += self.source[self.column_offset..offset].iter().filter(|&&byte| byte & 0xc0 != 0x80).count() as u32; // count UTF-8 start bytes
+        self.column_offset = offset;
+        Location {
+            offset,
+            line: self.line,
+            column: self.column,
+        }
+    }
+
+    /// Returns the byte `offset` bytes after the current position without consuming anything.
+    fn peek(&self, offset: usize) -> Option<u8> {
+        self.source.get(self.pos + offset).copied()
+    }
+
+    /// Consumes the next byte if it's `expected`.
+    fn eat(&mut self, expected: u8) -> bool {
+        if self.peek(0) == Some(expected) {
+            self.pos += 1;
+            true
         } else {
-            Ok(None)
+            false
         }
     }
-}
 
-impl<'a> Iterator for Lexer<'a> {
-    type Item = Result<(u32, Token, u32)>;
+    /// Consumes bytes as long as they match `pred`. Must not be used to consume newlines.
+    fn eat_while(&mut self, pred: impl Fn(u8) -> bool) {
+        while self.peek(0).map_or(false, &pred) {
+            self.pos += 1;
+        }
+    }
 
-    fn next(&mut self) -> Option<Result<(u32, Token, u32)>> {
-        let byte = loop {
-            let byte = match self.next_byte() {
-                Ok(Some(byte)) => byte,
-                Ok(None) => return None,
-                Err(e) => return Some(Err(e)),
-            };
-            match byte {
-                b' ' | b'\r' | b'\t' => {}
-                b'\n' => self.line += 1,
-                b'/' => match self.next_byte() {
-                    Ok(Some(b'/')) => loop {
-                        match self.next_byte() {
-                            Ok(Some(b'\n')) => {
-                                self.line += 1;
-                                break
-                            }
-                            Ok(Some(_)) => {}
-                            Ok(None) => return None,
-                            Err(e) => return Some(Err(e)),
-                        }
-                    },
-                    Ok(Some(next_byte)) => {
-                        self.peek = Some(next_byte);
-                        break byte
-                    }
-                    Ok(None) => break byte,
-                    Err(e) => return Some(Err(e)),
-                },
-                byte => break byte,
-            }
+    /// Consumes a run of digits in the given radix with optional `_` separators between them. For radixes other than 10, other letters and digits are consumed as well and reported as invalid.
+    fn digits(&mut self, radix: u32, kind: &str) -> Result<()> {
+        let start = self.pos;
+        if radix == 10 {
+            self.eat_while(|byte| byte.is_ascii_digit() || byte == b'_');
+        } else {
+            self.eat_while(|byte| byte.is_ascii_alphanumeric() || byte == b'_');
+        }
+        let digits = &self.source[start..self.pos];
+        if let Some(idx) = digits.iter().position(|&byte| byte != b'_' && !char::from(byte).is_digit(radix)) {
+            let msg = format!("Invalid digit '{}' in {} literal.", char::from(digits[idx]), kind);
+            return Err(Error::Compile {
+                msg,
+                span: Span::new(self.location_at(start + idx), self.location_at(start + idx + 1)),
+            })
+        }
+        if digits.is_empty() || digits[0] == b'_' || digits[digits.len() - 1] == b'_' {
+            return Err(Error::Compile {
+                msg: format!("Expected {} digits{}.", kind, if digits.is_empty() { "" } else { " around '_'" }),
+                span: Span::new(self.location_at(start), self.location()),
+            })
+        }
+        Ok(())
+    }
+
+    /// Lexes the rest of a number literal whose first digit is at `start`.
+    fn number(&mut self, start: usize) -> Result<f64> {
+        let radix = match (self.source[start], self.peek(0)) {
+            (b'0', Some(b'x')) => Some((16, "hexadecimal")),
+            (b'0', Some(b'o')) => Some((8, "octal")),
+            (b'0', Some(b'b')) => Some((2, "binary")),
+            (_, _) => None,
         };
-        let starting_line = self.line;
-        let token = match byte {
-            b'A'..=b'Z' | b'_' | b'a'..=b'z' => {
-                let mut ident = vec![byte];
-                loop {
-                    match self.next_byte() {
-                        Ok(Some(next_byte @ b'0'..=b'9')) | Ok(Some(next_byte @ b'A'..=b'Z')) | Ok(Some(next_byte @ b'_')) | Ok(Some(next_byte @ b'a'..=b'z')) => ident.push(next_byte),
-                        Ok(Some(next_byte)) => {
-                            self.peek = Some(next_byte);
-                            break
-                        }
-                        Ok(None) => break,
-                        Err(e) => return Some(Err(e)),
+        let n = if let Some((radix, kind)) = radix {
+            self.pos += 1;
+            let digits_start = self.pos;
+            self.digits(radix, kind)?;
+            // Keep the 64 most significant bits and remember whether any bit below them was set,
+            // so the value is only rounded once however many digits there are.
+            let bits_per_digit = radix.trailing_zeros();
+            let mut mantissa = 0u64;
+            let mut exponent = 0i32;
+            let mut sticky = false;
+            for &digit in self.source[digits_start..self.pos].iter().filter(|&&byte| byte != b'_') {
+                let digit = char::from(digit).to_digit(radix).expect("checked by Lexer::digits");
+                for bit in (0..bits_per_digit).rev().map(|shift| digit >> shift & 1 == 1) {
+                    if mantissa.leading_zeros() > 0 {
+                        mantissa = mantissa << 1 | u64::from(bit);
+                    } else {
+                        exponent = exponent.saturating_add(1);
+                        sticky |= bit;
                     }
                 }
-                let ident = unsafe { String::from_utf8_unchecked(ident) }; //SAFETY: bytes are ASCII
-                match &*ident {
-                    "and" => AND(starting_line),
-                    "class" => CLASS(starting_line),
-                    "else" => ELSE(starting_line),
-                    "false" => FALSE(starting_line),
-                    "for" => FOR(starting_line),
-                    "fun" => FUN(starting_line),
-                    "if" => IF(starting_line),
-                    "nil" => NIL(starting_line),
-                    "or" => OR(starting_line),
-                    "print" => PRINT(starting_line),
-                    "return" => RETURN(starting_line),
-                    "super" => SUPER(starting_line),
-                    "this" => THIS(starting_line),
-                    "true" => TRUE(starting_line),
-                    "var" => VAR(starting_line),
-                    "while" => WHILE(starting_line),
-                    _ => IDENTIFIER((starting_line, ident)),
-                }
             }
-            b'0'..=b'9' => {
-                let mut period_found = false;
-                let mut number = vec![byte];
-                loop {
-                    match self.next_byte() {
-                        Ok(Some(next_byte @ b'0'..=b'9')) => number.push(next_byte),
-                        Ok(Some(b'.')) if !period_found => {
-                            //TODO only consider part of the literal if followed by a digit; requires 2-byte lookahead
-                            period_found = true;
-                            number.push(b'.');
-                        }
-                        Ok(Some(next_byte)) => {
-                            self.peek = Some(next_byte);
-                            break
-                        }
-                        Ok(None) => break,
-                        Err(e) => return Some(Err(e)),
-                    }
-                }
-                let number = unsafe { String::from_utf8_unchecked(number) }; //SAFETY: bytes are ASCII
-                match number.parse() {
-                    Ok(n) => NUMBER((starting_line, n)),
-                    Err(e) => return Some(Err(e.into())),
-                }
+            // the lowest bit is below the rounding position since the mantissa is full whenever `sticky` is set
+            if sticky { mantissa |= 1 }
+            mantissa as f64 * 2.0f64.powi(exponent) // overflows to infinity, reported below
+        } else {
+            self.pos = start;
+            self.digits(10, "decimal")?;
+            // a `.` not followed by a digit is a separate token, e.g. a method call
+            if self.peek(0) == Some(b'.') && self.peek(1).map_or(false, |byte| byte.is_ascii_digit()) {
+                self.pos += 1;
+                self.digits(10, "decimal")?;
             }
-            b'(' => LEFT_PAREN(starting_line),
-            b')' => RIGHT_PAREN(starting_line),
-            b'{' => LEFT_BRACE(starting_line),
-            b'}' => RIGHT_BRACE(starting_line),
-            b';' => SEMICOLON(starting_line),
-            b',' => COMMA(starting_line),
-            b'.' => DOT(starting_line),
-            b'-' => MINUS(starting_line),
-            b'+' => PLUS(starting_line),
-            b'/' => SLASH(starting_line),
-            b'*' => STAR(starting_line),
-            b'!' => match self.next_byte() {
-                Ok(Some(b'=')) => BANG_EQUAL(starting_line),
-                Ok(Some(next_byte)) => {
-                    self.peek = Some(next_byte);
-                    BANG(starting_line)
+            if let Some(b'e') | Some(b'E') = self.peek(0) {
+                self.pos += 1;
+                if let Some(b'+') | Some(b'-') = self.peek(0) { self.pos += 1 }
+                if !self.peek(0).map_or(false, |byte| byte.is_ascii_digit()) {
+                    return Err(Error::Compile {
+                        msg: format!("Expected digits in exponent."),
+                        span: Span::new(self.location_at(start), self.location()),
+                    })
                 }
-                Ok(None) => BANG(starting_line),
-                Err(e) => return Some(Err(e)),
-            },
-            b'=' => match self.next_byte() {
-                Ok(Some(b'=')) => EQUAL_EQUAL(starting_line),
-                Ok(Some(next_byte)) => {
-                    self.peek = Some(next_byte);
-                    EQUAL(starting_line)
-                }
-                Ok(None) => EQUAL(starting_line),
-                Err(e) => return Some(Err(e)),
-            },
-            b'<' => match self.next_byte() {
-                Ok(Some(b'=')) => LESS_EQUAL(starting_line),
-                Ok(Some(next_byte)) => {
-                    self.peek = Some(next_byte);
-                    LESS(starting_line)
-                }
-                Ok(None) => LESS(starting_line),
-                Err(e) => return Some(Err(e)),
-            },
-            b'>' => match self.next_byte() {
-                Ok(Some(b'=')) => GREATER_EQUAL(starting_line),
-                Ok(Some(next_byte)) => {
-                    self.peek = Some(next_byte);
-                    GREATER(starting_line)
+                self.digits(10, "decimal")?;
+            }
+            let literal = self.source[start..self.pos].iter().filter(|&&byte| byte != b'_').map(|&byte| char::from(byte)).collect::<String>();
+            literal.parse().expect("checked by Lexer::digits")
+        };
+        if n.is_finite() {
+            Ok(n)
+        } else {
+            Err(Error::Compile {
+                msg: format!("Number literal is too large."),
+                span: Span::new(self.location_at(start), self.location()),
+            })
+        }
+    }
+
+    /// Lexes the next token, or returns `None` at the end of the source.
+    fn token(&mut self) -> Option<Result<(Location, Token<'a>, Location)>> {
+        loop {
+            match self.peek(0) {
+                Some(b' ') | Some(b'\r') | Some(b'\t') => self.pos += 1,
+                Some(b'\n') => self.newline(),
+                Some(b'/') if self.peek(1) == Some(b'/') => self.eat_while(|byte| byte != b'\n'),
+                Some(_) => break,
+                None => return None,
+            }
+        }
+        let start = self.pos;
+        let start_location = self.location();
+        let byte = self.source[start];
+        self.pos += 1;
+        let token = match byte {
+            b'A'..=b'Z' | b'_' | b'a'..=b'z' => {
+                self.eat_while(|byte| byte.is_ascii_alphanumeric() || byte == b'_');
+                let ident = unsafe { str::from_utf8_unchecked(&self.source[start..self.pos]) }; //SAFETY: bytes are ASCII
+                match ident {
+                    "and" => AND,
+                    "class" => CLASS,
+                    "else" => ELSE,
+                    "false" => FALSE,
+                    "for" => FOR,
+                    "fun" => FUN,
+                    "if" => IF,
+                    "nil" => NIL,
+                    "or" => OR,
+                    "print" => PRINT,
+                    "return" => RETURN,
+                    "super" => SUPER,
+                    "this" => THIS,
+                    "true" => TRUE,
+                    "var" => VAR,
+                    "while" => WHILE,
+                    _ => IDENTIFIER(ident),
                 }
-                Ok(None) => GREATER(starting_line),
+            }
+            b'0'..=b'9' => match self.number(start) {
+                Ok(n) => NUMBER(n),
                 Err(e) => return Some(Err(e)),
             },
+            b'(' => LEFT_PAREN,
+            b')' => RIGHT_PAREN,
+            b'{' => LEFT_BRACE,
+            b'}' => RIGHT_BRACE,
+            b';' => SEMICOLON,
+            b',' => COMMA,
+            b'.' => DOT,
+            b'-' => MINUS,
+            b'+' => PLUS,
+            b'/' => SLASH,
+            b'*' => STAR,
+            b'!' => if self.eat(b'=') { BANG_EQUAL } else { BANG },
+            b'=' => if self.eat(b'=') { EQUAL_EQUAL } else { EQUAL },
+            b'<' => if self.eat(b'=') { LESS_EQUAL } else { LESS },
+            b'>' => if self.eat(b'=') { GREATER_EQUAL } else { GREATER },
             b'"' => {
-                let mut buf = Vec::default();
                 loop {
-                    match self.next_byte() {
-                        Ok(Some(b'\n')) => {
-                            self.line += 1;
-                            buf.push(b'\n');
-                        }
-                        Ok(Some(b'"')) => break,
-                        Ok(Some(byte)) => buf.push(byte),
-                        Ok(None) => return Some(Err(Error::Compile {
+                    match self.peek(0) {
+                        Some(b'"') => break,
+                        Some(b'\n') => self.newline(),
+                        Some(_) => self.pos += 1,
+                        None => return Some(Err(Error::Compile {
                             msg: format!("Unterminated string."),
-                            line: self.line,
+                            span: Span::new(start_location, self.location()),
                         })),
-                        Err(e) => return Some(Err(e)),
                     }
                 }
-                match String::from_utf8(buf) {
-                    Ok(s) => STRING((starting_line, self.line, s)),
-                    Err(e) => return Some(Err(e.into())),
+                self.pos += 1; // closing quote
+                match str::from_utf8(&self.source[start + 1..self.pos - 1]) {
+                    Ok(s) => STRING(s),
+                    Err(_) => return Some(Err(Error::Compile {
+                        msg: format!("Invalid UTF-8 in string."),
+                        span: Span::new(start_location, self.location()),
+                    })),
                 }
             }
-            _ => return Some(Err(Error::Compile {
-                msg: format!("Unexpected character."),
-                line: self.line,
-            })),
+            _ => {
+                self.eat_while(|byte| byte & 0xc0 == 0x80); // the rest of a multibyte character
+                return Some(Err(Error::Compile {
+                    msg: format!("Unexpected character."),
+                    span: Span::new(start_location, self.location()),
+                }))
+            }
         };
-        Some(Ok((starting_line, token, self.line)))
+        Some(Ok((start_location, token, self.location())))
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<(Location, Token<'a>, Location)>;
+
+    fn next(&mut self) -> Option<Result<(Location, Token<'a>, Location)>> {
+        match self.token()? {
+            Err(Error::Compile { msg, span }) => {
+                self.errors.push(Error::Compile { msg, span });
+                Some(Ok((span.start, ERROR, span.end)))
+            }
+            result => Some(result),
+        }
     }
 }
 
 #[allow(non_camel_case_types)]
 #[derive(Debug, Clone)]
-pub enum Token {
-    AND(u32),
-    CLASS(u32),
-    ELSE(u32),
-    FALSE(u32),
-    FOR(u32),
-    FUN(u32),
-    IF(u32),
-    NIL(u32),
-    OR(u32),
-    PRINT(u32),
-    RETURN(u32),
-    SUPER(u32),
-    THIS(u32),
-    TRUE(u32),
-    VAR(u32),
-    WHILE(u32),
-    IDENTIFIER((u32, String)),
-    NUMBER((u32, f64)),
-    LEFT_PAREN(u32),
-    RIGHT_PAREN(u32),
-    LEFT_BRACE(u32),
-    RIGHT_BRACE(u32),
-    SEMICOLON(u32),
-    COMMA(u32),
-    DOT(u32),
-    MINUS(u32),
-    PLUS(u32),
-    SLASH(u32),
-    STAR(u32),
-    BANG_EQUAL(u32),
-    BANG(u32),
-    EQUAL_EQUAL(u32),
-    EQUAL(u32),
-    LESS_EQUAL(u32),
-    LESS(u32),
-    GREATER_EQUAL(u32),
-    GREATER(u32),
-    STRING((u32, u32, String)),
+pub enum Token<'a> {
+    AND,
+    CLASS,
+    ELSE,
+    FALSE,
+    FOR,
+    FUN,
+    IF,
+    NIL,
+    OR,
+    PRINT,
+    RETURN,
+    SUPER,
+    THIS,
+    TRUE,
+    VAR,
+    WHILE,
+    IDENTIFIER(&'a str),
+    NUMBER(f64),
+    LEFT_PAREN,
+    RIGHT_PAREN,
+    LEFT_BRACE,
+    RIGHT_BRACE,
+    SEMICOLON,
+    COMMA,
+    DOT,
+    MINUS,
+    PLUS,
+    SLASH,
+    STAR,
+    BANG_EQUAL,
+    BANG,
+    EQUAL_EQUAL,
+    EQUAL,
+    LESS_EQUAL,
+    LESS,
+    GREATER_EQUAL,
+    GREATER,
+    STRING(&'a str),
+    /// An invalid token. The error is in `Lexer::errors`.
+    ERROR,
 }
 
-impl fmt::Display for Token {
+impl fmt::Display for Token<'_> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            AND(_) => write!(f, "and"),
-            CLASS(_) => write!(f, "class"),
-            ELSE(_) => write!(f, "else"),
-            FALSE(_) => write!(f, "false"),
-            FOR(_) => write!(f, "for"),
-            FUN(_) => write!(f, "fun"),
-            IF(_) => write!(f, "if"),
-            NIL(_) => write!(f, "nil"),
-            OR(_) => write!(f, "or"),
-            PRINT(_) => write!(f, "print"),
-            RETURN(_) => write!(f, "return"),
-            SUPER(_) => write!(f, "super"),
-            THIS(_) => write!(f, "this"),
-            TRUE(_) => write!(f, "true"),
-            VAR(_) => write!(f, "var"),
-            WHILE(_) => write!(f, "while"),
-            IDENTIFIER((_, name)) => name.fmt(f),
-            NUMBER((_, n)) => n.fmt(f),
-            LEFT_PAREN(_) => write!(f, "("),
-            RIGHT_PAREN(_) => write!(f, ")"),
-            LEFT_BRACE(_) => write!(f, "{{"),
-            RIGHT_BRACE(_) => write!(f, "}}"),
-            SEMICOLON(_) => write!(f, ";"),
-            COMMA(_) => write!(f, ","),
-            DOT(_) => write!(f, "."),
-            MINUS(_) => write!(f, "-"),
-            PLUS(_) => write!(f, "+"),
-            SLASH(_) => write!(f, "/"),
-            STAR(_) => write!(f, "*"),
-            BANG_EQUAL(_) => write!(f, "!="),
-            BANG(_) => write!(f, "!"),
-            EQUAL_EQUAL(_) => write!(f, "=="),
-            EQUAL(_) => write!(f, "="),
-            LESS_EQUAL(_) => write!(f, "<="),
-            LESS(_) => write!(f, "<"),
-            GREATER_EQUAL(_) => write!(f, ">="),
-            GREATER(_) => write!(f, ">"),
-            STRING((_, _, s)) => write!(f, "\"{}\"", s),
+            AND => write!(f, "and"),
+            CLASS => write!(f, "class"),
+            ELSE => write!(f, "else"),
+            FALSE => write!(f, "false"),
+            FOR => write!(f, "for"),
+            FUN => write!(f, "fun"),
+            IF => write!(f, "if"),
+            NIL => write!(f, "nil"),
+            OR => write!(f, "or"),
+            PRINT => write!(f, "print"),
+            RETURN => write!(f, "return"),
+            SUPER => write!(f, "super"),
+            THIS => write!(f, "this"),
+            TRUE => write!(f, "true"),
+            VAR => write!(f, "var"),
+            WHILE => write!(f, "while"),
+            IDENTIFIER(name) => name.fmt(f),
+            NUMBER(n) => n.fmt(f),
+            LEFT_PAREN => write!(f, "("),
+            RIGHT_PAREN => write!(f, ")"),
+            LEFT_BRACE => write!(f, "{{"),
+            RIGHT_BRACE => write!(f, "}}"),
+            SEMICOLON => write!(f, ";"),
+            COMMA => write!(f, ","),
+            DOT => write!(f, "."),
+            MINUS => write!(f, "-"),
+            PLUS => write!(f, "+"),
+            SLASH => write!(f, "/"),
+            STAR => write!(f, "*"),
+            BANG_EQUAL => write!(f, "!="),
+            BANG => write!(f, "!"),
+            EQUAL_EQUAL => write!(f, "=="),
+            EQUAL => write!(f, "="),
+            LESS_EQUAL => write!(f, "<="),
+            LESS => write!(f, "<"),
+            GREATER_EQUAL => write!(f, ">="),
+            GREATER => write!(f, ">"),
+            STRING(s) => write!(f, "\"{}\"", s),
+            ERROR => write!(f, "<error>"),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the tokens of the source, or the message of the first error.
+    fn lex(source: &str) -> std::result::Result<Vec<Token<'_>>, String> {
+        let mut lexer = Lexer::new(source.as_bytes());
+        let mut tokens = Vec::default();
+        while let Some(token) = lexer.token() {
+            match token {
+                Ok((_, token, _)) => tokens.push(token),
+                Err(Error::Compile { msg, .. }) => return Err(msg),
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        Ok(tokens)
+    }
+
+    #[test]
+    fn trailing_dot() {
+        let tokens = lex("123.").expect("failed to lex");
+        assert!(matches!(*tokens, [NUMBER(n), DOT] if n == 123.0), "{:?}", tokens);
+        assert!(crate::parse(b"print 123.;").is_err());
+    }
+
+    #[test]
+    fn leading_dot() {
+        let tokens = lex(".5").expect("failed to lex");
+        assert!(matches!(*tokens, [DOT, NUMBER(n)] if n == 5.0), "{:?}", tokens);
+        assert!(crate::parse(b"print .5;").is_err());
+    }
+
+    #[test]
+    fn fraction() {
+        let tokens = lex("1.5").expect("failed to lex");
+        assert!(matches!(*tokens, [NUMBER(n)] if n == 1.5), "{:?}", tokens);
+        assert!(crate::parse(b"print 1.5;").is_ok());
+    }
+
+    fn number(source: &str) -> std::result::Result<f64, String> {
+        match *lex(source)? {
+            [NUMBER(n)] => Ok(n),
+            ref tokens => panic!("expected a single number, got {:?}", tokens),
+        }
+    }
+
+    #[test]
+    fn radix_literals() {
+        assert_eq!(number("0xff"), Ok(255.0));
+        assert_eq!(number("0xFF"), Ok(255.0));
+        assert_eq!(number("0o17"), Ok(15.0));
+        assert_eq!(number("0b1010"), Ok(10.0));
+        assert_eq!(number("0x0"), Ok(0.0));
+    }
+
+    #[test]
+    fn radix_literals_round_once() {
+        // 2^55 + 5 is between the representable values 2^55 and 2^55 + 8
+        let literal = format!("0b1{}101", "0".repeat(52));
+        assert_eq!(number(&literal), Ok(2.0f64.powi(55) + 8.0));
+        assert_eq!(number(&format!("0x1{}", "0".repeat(31))), Ok(2.0f64.powi(124)));
+        assert_eq!(number(&format!("0x1{}", "0".repeat(32))), Ok(2.0f64.powi(128)));
+        // 2^100 + 2^47 + 1 is just above halfway between 2^100 and the next representable value, 2^100 + 2^48
+        let literal = format!("0b1{}1{}1", "0".repeat(52), "0".repeat(46));
+        assert_eq!(number(&literal), Ok(2.0f64.powi(100) + 2.0f64.powi(48)));
+        // exactly halfway rounds to even
+        let literal = format!("0b1{}1{}", "0".repeat(52), "0".repeat(47));
+        assert_eq!(number(&literal), Ok(2.0f64.powi(100)));
+        assert_eq!(number(&format!("0b{}{}", "1".repeat(53), "0".repeat(971))), Ok(f64::MAX));
+        assert_eq!(number(&format!("0b{}0{}", "1".repeat(53), "1".repeat(970))), Ok(f64::MAX));
+        assert_eq!(number(&format!("0b{}{}", "1".repeat(54), "0".repeat(970))), Err("Number literal is too large.".to_owned()));
+        assert_eq!(number(&format!("0x1{}", "0".repeat(256))), Err("Number literal is too large.".to_owned()));
+    }
+
+    #[test]
+    fn separators() {
+        assert_eq!(number("1_000"), Ok(1000.0));
+        assert_eq!(number("1__0"), Ok(10.0));
+        assert_eq!(number("0b1_0"), Ok(2.0));
+        assert_eq!(number("1_0.2_5"), Ok(10.25));
+        assert_eq!(number("0x_1"), Err("Expected hexadecimal digits around '_'.".to_owned()));
+        assert_eq!(number("0o1_"), Err("Expected octal digits around '_'.".to_owned()));
+        assert_eq!(number("1_"), Err("Expected decimal digits around '_'.".to_owned()));
+        assert_eq!(number("1_.5"), Err("Expected decimal digits around '_'.".to_owned()));
+    }
+
+    #[test]
+    fn exponents() {
+        assert_eq!(number("1e3"), Ok(1000.0));
+        assert_eq!(number("1E3"), Ok(1000.0));
+        assert_eq!(number("1e+3"), Ok(1000.0));
+        assert_eq!(number("1.5e-3"), Ok(0.0015));
+        assert_eq!(number("1e"), Err("Expected digits in exponent.".to_owned()));
+        assert_eq!(number("1e-"), Err("Expected digits in exponent.".to_owned()));
+        assert_eq!(number("1e999"), Err("Number literal is too large.".to_owned()));
+    }
+
+    #[test]
+    fn invalid_digits() {
+        assert_eq!(number("0b2"), Err("Invalid digit '2' in binary literal.".to_owned()));
+        assert_eq!(number("0o8"), Err("Invalid digit '8' in octal literal.".to_owned()));
+        assert_eq!(number("0xg"), Err("Invalid digit 'g' in hexadecimal literal.".to_owned()));
+        assert_eq!(number("0x"), Err("Expected hexadecimal digits.".to_owned()));
+    }
+}
@@ -2,51 +2,103 @@
 
 use {
     std::{
-        fs::File,
+        fs::{
+            self,
+            File,
+        },
         io::{
             self,
-            Cursor,
-            SeekFrom,
             prelude::*,
         },
         path::PathBuf,
+        str,
+        sync::atomic::Ordering::SeqCst,
+    },
+    lalrpop_util::{
+        ErrorRecovery,
+        ParseError,
+        lalrpop_mod,
     },
-    byteorder::ReadBytesExt as _,
-    lalrpop_util::lalrpop_mod,
     structopt::StructOpt,
     crate::{
+        diagnostic::{
+            ErrorFormat,
+            Report,
+            SourceFile,
+        },
         error::{
             Error,
             Result,
         },
-        lexer::Lexer,
+        lexer::{
+            Lexer,
+            Token,
+        },
+        span::Location,
+        resolver::{
+            LintGroup,
+            Lints,
+            Warning,
+        },
         value::FunctionInner,
         vm::Vm,
     },
 };
 
+mod assembler;
 mod ast;
 mod compiler;
+mod diagnostic;
 mod error;
+mod heap;
+mod intern;
 mod lexer;
 mod native;
 lalrpop_mod!(parser);
+mod repl;
+mod resolver;
+mod span;
 mod value;
+mod verifier;
 mod vm;
 
-fn parse(source: impl Read) -> Result<Vec<ast::Stmt>> {
-    Ok(parser::ProgramParser::new().parse(Lexer::new(Box::new(source)))?)
+fn parse(source: &[u8]) -> Result<Vec<ast::Stmt>> {
+    parse_with(source, |errors, lexer| parser::ProgramParser::new().parse(errors, lexer))
 }
 
-fn compile(mut source: impl Read + Seek) -> Result<FunctionInner> {
-    match source.read_u8() {
-        Ok(0xc0) => FunctionInner::read(&mut source, true),
-        Ok(_) => {
-            source.seek(SeekFrom::Current(-1))?; // first byte was not the bytecode magic byte, unread it
-            compiler::compile(parse(source)?)
+/// Runs a parser that recovers from syntax errors, and returns all lexer and parser errors if there were any.
+fn parse_with<'a, T>(source: &'a [u8], parse: impl FnOnce(&mut Vec<ErrorRecovery<Location, Token<'a>, Error>>, &mut Lexer<'a>) -> Result<T, ParseError<Location, Token<'a>, Error>>) -> Result<T> {
+    let mut lexer = Lexer::new(source);
+    let mut recovered = Vec::default();
+    let result = parse(&mut recovered, &mut lexer);
+    let mut parse_errors = recovered.into_iter().map(|recovery| recovery.error).collect::<Vec<_>>();
+    let result = match result {
+        Ok(result) => Some(result),
+        Err(e) => {
+            parse_errors.push(e);
+            None
         }
-        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => compiler::compile(Vec::default()),
-        Err(e) => Err(e.into()),
+    };
+    let mut errors = lexer.errors;
+    errors.extend(parse_errors.into_iter().filter(|e| !matches!(e,
+        // already reported by the lexer
+        ParseError::UnrecognizedToken { token: (_, Token::ERROR, _), .. }
+        | ParseError::ExtraToken { token: (_, Token::ERROR, _) }
+    )).map(Error::from));
+    Error::check(errors)?;
+    Ok(result.expect("parser failed without an error"))
+}
+
+/// Compiles source code or loads bytecode, returning any warnings about the source code along with the function.
+fn compile(source: &[u8], vm: &mut Vm, lints: &Lints) -> Result<(FunctionInner, Vec<Warning>)> {
+    if let Some((&0xc0, mut bytecode)) = source.split_first() {
+        let (mut function, names) = FunctionInner::read_bytecode(&mut bytecode, &vm.strings)?;
+        vm.global_table.link(&mut function, &names);
+        Ok((function, Vec::default()))
+    } else {
+        let stmts = parse(source)?;
+        let warnings = resolver::resolve(&stmts, vm.global_names(), lints);
+        Ok((compiler::compile(stmts, &mut vm.global_table, &vm.strings)?, warnings))
     }
 }
 
@@ -55,48 +107,80 @@ struct Args {
     /// Only compile the script and dump the bytecode to stdout.
     #[structopt(long)]
     compile: bool,
-    /// When used with `--compile`, dump the bytecode to the given path instead of stdout.
+    /// Read the script as bytecode assembly (the format printed by `--disassemble`) and dump the assembled bytecode to stdout.
+    #[structopt(long)]
+    assemble: bool,
+    /// When used with `--compile` or `--assemble`, dump the bytecode to the given path instead of stdout.
     #[structopt(short, long, parse(from_os_str))]
     output: Option<PathBuf>,
     /// Print a disassembly of the bytecode before running/dumping it.
     #[structopt(long)]
     disassemble: bool,
+    /// Print the value stack, call depth, and instruction before executing each instruction.
+    #[structopt(long)]
+    trace: bool,
+    /// Only trace instructions in the function with this name (implies --trace). Can be given multiple times. Top-level code is called `<script>`.
+    #[structopt(long = "trace-fn", number_of_values = 1)]
+    trace_fn: Vec<String>,
+    /// Raise an “Out of memory.” runtime error when strings, closures, and other heap objects would take up more than this many bytes.
+    #[structopt(long)]
+    max_heap: Option<usize>,
+    /// How to print errors: `human` shows the offending source code, `json` prints one JSON object per error, `plain` only the line numbers, as expected by the craftinginterpreters test suite.
+    #[structopt(long, default_value = "human")]
+    error_format: ErrorFormat,
+    /// Show warnings from the given lint, or `warnings` for all of them. Takes precedence over `-A`. All lints are on by default.
+    #[structopt(short = "W", long = "warn", number_of_values = 1)]
+    warn: Vec<LintGroup>,
+    /// Hide warnings from the given lint, or `warnings` for all of them. Can be given multiple times.
+    #[structopt(short = "A", long = "allow", number_of_values = 1)]
+    allow: Vec<LintGroup>,
     /// The path to a Lox script or bytecode dump that will be run. If omitted, a repl is started.
     #[structopt(parse(from_os_str))]
     script: Option<PathBuf>,
 }
 
 #[wheel::main(custom_exit)]
-fn main(args: Args) -> Result {
+fn main(args: Args) -> Result<(), Report> {
+    let format = args.error_format;
+    let script = match args.script.as_ref().map(|path| fs::read(path).map(|text| SourceFile { name: path.display().to_string(), text })).transpose() {
+        Ok(script) => script,
+        Err(e) => return Err(Report { error: e.into(), format, source: None }),
+    };
+    run(args, script.as_ref()).map_err(|error| Report { error, format, source: script })
+}
+
+fn run(args: Args, script: Option<&SourceFile>) -> Result {
     let mut vm = Vm::new();
-    if let Some(script) = args.script {
-        let bytecode = compile(File::open(script)?)?;
-        if args.disassemble { bytecode.disassemble(); }
-        if args.compile {
+    vm.heap_limit = args.max_heap;
+    vm.trace = args.trace || !args.trace_fn.is_empty();
+    vm.trace_functions = args.trace_fn;
+    let interrupted = vm.interrupt_handle();
+    ctrlc::set_handler(move || interrupted.store(true, SeqCst))?;
+    let lints = Lints::new(&args.warn, &args.allow);
+    if let Some(script) = script {
+        let bytecode = if args.assemble {
+            let (mut function, names) = assembler::assemble(str::from_utf8(&script.text)?, &vm.strings)?;
+            vm.global_table.link(&mut function, &names);
+            function
+        } else {
+            let (function, warnings) = compile(&script.text, &mut vm, &lints)?;
+            diagnostic::print_warnings(&warnings, args.error_format, Some(script));
+            function
+        };
+        if args.disassemble { bytecode.disassemble(vm.global_table.names()); }
+        if args.compile || args.assemble {
             let mut output = if let Some(out_path) = args.output {
                 Box::new(File::create(out_path)?) as Box<dyn Write>
             } else {
                 Box::new(io::stdout())
             };
-            bytecode.write(&mut output)?;
+            bytecode.write_bytecode(vm.global_table.names(), &mut output)?;
         } else {
             vm.interpret(bytecode)?;
         }
     } else {
-        if args.compile { return Err(Error::CompileRepl) }
-        // repl
-        let stdin = io::stdin();
-        let mut stdout = io::stdout();
-        loop {
-            print!("> ");
-            stdout.flush()?;
-            let mut line = String::default();
-            stdin.read_line(&mut line)?;
-            if line.trim().is_empty() { break }
-            let bytecode = compile(Cursor::new(line.as_bytes()))?;
-            if args.disassemble { bytecode.disassemble(); }
-            vm.interpret(bytecode)?;
-        }
+        if args.compile || args.assemble { return Err(Error::CompileRepl) }
+        if let Some(exit_code) = repl::run(&mut vm, args.disassemble, args.error_format, &lints)? { std::process::exit(exit_code) }
     }
     Ok(())
 }
@@ -1,28 +1,42 @@
 use {
     std::{
-        collections::HashMap,
         convert::TryInto as _,
         time::Instant,
     },
-    gc::Gc,
     once_cell::sync::Lazy,
-    crate::value::Value,
+    crate::{
+        heap,
+        intern::{
+            InternedString,
+            Interner,
+        },
+        value::Value,
+    },
 };
 
 macro_rules! register {
     ($($f:ident,)*) => {
         static FUNCTIONS: Lazy<Vec<NativeFn>> = Lazy::new(|| {
-            let functions = vec![$($f as NativeFn)*];
+            let functions = vec![$($f as NativeFn,)*];
             assert!(functions.len() < 256);
             functions
         });
 
-        pub(crate) fn all() -> HashMap<Gc<String>, Gc<Value>> {
-            let mut map = HashMap::default();
-            $(
-                map.insert(Gc::new(stringify!($f).to_owned()), Value::new($f as NativeFn));
-            )*
-            map
+        /// All native functions with their names, in the order they're registered in.
+        pub(crate) fn all(strings: &Interner) -> Vec<(InternedString, Value)> {
+            vec![$(
+                (strings.intern(stringify!($f)), Value::new($f as NativeFn)),
+            )*]
+        }
+
+        const NAMES: &[&str] = &[$(stringify!($f),)*];
+
+        pub(crate) fn name(f: NativeFn) -> &'static str {
+            NAMES[usize::from(serialize(f))]
+        }
+
+        pub(crate) fn by_name(name: &str) -> Option<NativeFn> {
+            NAMES.iter().position(|&iter_name| iter_name == name).map(|idx| FUNCTIONS[idx])
         }
 
         pub(crate) fn deserialize(fn_id: u8) -> Option<NativeFn> {
@@ -41,12 +55,17 @@ macro_rules! register {
 
 static EPOCH: Lazy<Instant> = Lazy::new(|| Instant::now());
 
-pub(crate) type NativeFn = fn(&[Gc<Value>]) -> Gc<Value>;
+pub(crate) type NativeFn = fn(&[Value]) -> Value;
 
-fn clock(_: &[Gc<Value>]) -> Gc<Value> {
+fn clock(_: &[Value]) -> Value {
     Value::new(EPOCH.elapsed().as_secs_f64())
 }
 
+fn heap_usage(_: &[Value]) -> Value {
+    Value::new(heap::bytes_allocated() as f64)
+}
+
 register! {
     clock,
+    heap_usage,
 }
@@ -0,0 +1,243 @@
+use {
+    std::{
+        borrow::Cow,
+        fs,
+        time::Instant,
+    },
+    lalrpop_util::ParseError,
+    rustyline::{
+        Context,
+        Editor,
+        Helper,
+        completion::Completer,
+        error::ReadlineError,
+        highlight::{
+            Highlighter,
+            MatchingBracketHighlighter,
+        },
+        hint::Hinter,
+        validate::Validator,
+    },
+    crate::{
+        compiler,
+        diagnostic::{
+            self,
+            ErrorFormat,
+            SourceFile,
+        },
+        error::{
+            Error,
+            Result,
+        },
+        ast::Stmt,
+        lexer,
+        parser::ReplInputParser,
+        resolver::{
+            self,
+            Lints,
+        },
+        value::Value,
+        vm::Vm,
+    },
+};
+
+const COMMANDS: [&str; 7] = [":dis", ":globals", ":help", ":load", ":quit", ":reset", ":time"];
+
+/// Completes keywords and global names, and highlights matching brackets.
+struct ReplHelper {
+    globals: Vec<String>,
+    brackets: MatchingBracketHighlighter,
+}
+
+impl Helper for ReplHelper {}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        if line.starts_with(':') && !line[..pos].contains(char::is_whitespace) {
+            return Ok((0, COMMANDS.iter().filter(|command| command.starts_with(&line[..pos])).map(|&command| command.to_owned()).collect()))
+        }
+        let start = line[..pos].rfind(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).map_or(0, |idx| idx + 1);
+        let prefix = &line[start..pos];
+        if prefix.is_empty() || prefix.starts_with(|c: char| c.is_ascii_digit()) { return Ok((pos, Vec::default())) }
+        let mut candidates = lexer::KEYWORDS.iter().map(|&keyword| keyword.to_owned())
+            .chain(self.globals.iter().cloned())
+            .filter(|name| name.starts_with(prefix))
+            .collect::<Vec<_>>();
+        candidates.sort();
+        candidates.dedup();
+        Ok((start, candidates))
+    }
+}
+
+impl Highlighter for ReplHelper {
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        self.brackets.highlight(line, pos)
+    }
+
+    fn highlight_char(&self, line: &str, pos: usize) -> bool {
+        self.brackets.highlight_char(line, pos)
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Validator for ReplHelper {}
+
+fn parse(source: &str) -> Result<Vec<Stmt>> {
+    crate::parse_with(source.as_bytes(), |errors, lexer| ReplInputParser::new().parse(errors, lexer))
+}
+
+/// Compiles and runs an input. Warnings are only printed if it compiles, so they don't end up between its compile errors.
+fn eval(vm: &mut Vm, stmts: Vec<Stmt>, source: &str, disassemble: bool, error_format: ErrorFormat, lints: &Lints) -> Result {
+    let warnings = resolver::resolve(&stmts, vm.global_names(), lints);
+    let bytecode = compiler::compile_repl(stmts, &mut vm.global_table, &vm.strings)?;
+    diagnostic::print_warnings(&warnings, error_format, Some(&SourceFile { name: "<repl>".to_owned(), text: source.as_bytes().to_owned() }));
+    if disassemble { bytecode.disassemble(vm.global_table.names()); }
+    vm.interpret(bytecode)
+}
+
+/// Runs a meta-command, given the input line without the leading `:`.
+fn command(vm: &mut Vm, line: &str, disassemble: bool, error_format: ErrorFormat, lints: &Lints) -> Result {
+    let (cmd, arg) = line.split_once(char::is_whitespace).map_or((line, ""), |(cmd, arg)| (cmd, arg.trim()));
+    match cmd {
+        "dis" => match vm.global(arg) {
+            Some(Value::Closure(closure)) => closure.function.borrow().disassemble(vm.global_table.names()),
+            Some(Value::Function(function)) => function.borrow().disassemble(vm.global_table.names()),
+            Some(_) => eprintln!("{} is not a Lox function", arg),
+            None => eprintln!("no global named {:?}", arg),
+        },
+        "globals" => {
+            let mut names = vm.global_names().collect::<Vec<_>>();
+            names.sort();
+            for name in names {
+                println!("{} = {}", name, vm.global(name).expect("global name without value"));
+            }
+        }
+        "help" => {
+            println!(":dis <name>  disassemble a global function");
+            println!(":globals     list global variables");
+            println!(":help        show this help");
+            println!(":load <path> run a Lox script in this session");
+            println!(":quit        exit the repl");
+            println!(":reset       remove all global variables");
+            println!(":time <code> run code and show how long it took");
+        }
+        "load" => {
+            let source = SourceFile { name: arg.to_owned(), text: fs::read(arg)? };
+            let (bytecode, warnings) = crate::compile(&source.text, vm, lints)?;
+            diagnostic::print_warnings(&warnings, error_format, Some(&source));
+            if disassemble { bytecode.disassemble(vm.global_table.names()); }
+            vm.interpret(bytecode)?;
+        }
+        "reset" => vm.reset(),
+        "time" => {
+            let start = Instant::now();
+            let result = parse(arg).and_then(|stmts| eval(vm, stmts, arg, disassemble, error_format, lints));
+            eprintln!("took {:?}", start.elapsed());
+            result?;
+        }
+        _ => eprintln!("unknown command :{}, type :help for a list of commands", cmd),
+    }
+    Ok(())
+}
+
+/// Runs an interactive session on the given VM. Returns the exit code of the last input if it failed.
+pub(crate) fn run(vm: &mut Vm, disassemble: bool, error_format: ErrorFormat, lints: &Lints) -> Result<Option<i32>> {
+    let history_path = dirs::home_dir().map(|home| home.join(".rlox_history"));
+    let mut editor = Editor::new();
+    editor.set_helper(Some(ReplHelper {
+        globals: Vec::default(),
+        brackets: MatchingBracketHighlighter::new(),
+    }));
+    if let Some(ref history_path) = history_path {
+        let _ = editor.load_history(history_path); // the history file doesn't exist on first run
+    }
+    let mut last_exit_code = None;
+    let mut source = String::default();
+    loop {
+        editor.helper_mut().expect("repl helper missing").globals = vm.global_names().map(str::to_owned).collect();
+        let line = match editor.readline(if source.is_empty() { "> " } else { "... " }) {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => {
+                // discard the current input
+                eprintln!("Interrupted.");
+                source.clear();
+                continue
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e.into()),
+        };
+        vm.clear_interrupt(); // a new evaluation starts with this input
+        // report errors without ending the session, the VM keeps its globals
+        let is_command = source.is_empty() && line.trim().starts_with(':');
+        let result = if let (true, Some(cmd)) = (is_command, line.trim().strip_prefix(':')) {
+            editor.add_history_entry(line.trim());
+            if cmd == "quit" { break }
+            command(vm, cmd, disassemble, error_format, lints)
+        } else {
+            source.push_str(&line);
+            source.push('\n');
+            match parse(&source) {
+                Err(Error::Parse(ParseError::UnrecognizedEOF { .. })) => continue, // incomplete input, show a continuation prompt
+                stmts => stmts.and_then(|stmts| eval(vm, stmts, &source, disassemble, error_format, lints)),
+            }
+        };
+        if let Err(ref e) = result {
+            // compile errors point into this input, but runtime errors may be in functions from earlier inputs
+            let input = match e {
+                Error::Compile { .. } | Error::Parse(_) | Error::Multiple(_) if !is_command => Some(SourceFile { name: format!("<repl>"), text: source.as_bytes().to_owned() }),
+                _ => None,
+            };
+            diagnostic::print(e, error_format, input.as_ref());
+        }
+        if !source.trim().is_empty() { editor.add_history_entry(source.trim_end()); }
+        source.clear();
+        last_exit_code = result.err().map(|e| e.exit_code());
+    }
+    if let Some(ref history_path) = history_path {
+        let _ = editor.save_history(history_path); // not being able to save the history shouldn't affect the exit code
+    }
+    Ok(last_exit_code)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        std::str,
+        crate::{
+            compiler,
+            vm::Vm,
+        },
+        super::parse,
+    };
+
+    /// Compiles repl input and counts the `Echo` instructions in its bytecode.
+    fn echoes(source: &str) -> usize {
+        let mut vm = Vm::new();
+        let stmts = parse(source).unwrap_or_else(|e| panic!("failed to parse: {}", e));
+        let function = compiler::compile_repl(stmts, &mut vm.global_table, &vm.strings).unwrap_or_else(|e| panic!("failed to compile: {}", e));
+        let mut disassembly = Vec::default();
+        function.write_disassembly(vm.global_table.names(), &mut disassembly).expect("failed to disassemble");
+        let disassembly = str::from_utf8(&disassembly).expect("disassembly is not UTF-8");
+        disassembly.lines().filter(|line| line.split_whitespace().any(|word| word == "Echo")).count()
+    }
+
+    #[test]
+    fn top_level_expressions() {
+        assert_eq!(echoes("1 + 2"), 1);
+        assert_eq!(echoes("1; 2;"), 2);
+        assert_eq!(echoes("var a = 1; a = 2;"), 1);
+    }
+
+    #[test]
+    fn nested_expressions() {
+        assert_eq!(echoes("var i = 0; while (i < 3) i = i + 1;"), 0);
+        assert_eq!(echoes("if (true) 1; else 2;"), 0);
+        assert_eq!(echoes("{ 1; }"), 0);
+        assert_eq!(echoes("fun f() { 1; }"), 0);
+    }
+}
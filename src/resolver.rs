@@ -0,0 +1,312 @@
+//! A pass over the AST between parsing and compiling that warns about code which is valid Lox but probably a mistake. It never changes what the program does.
+
+use {
+    std::{
+        collections::HashSet,
+        str::FromStr,
+    },
+    crate::{
+        ast::*,
+        span::Span,
+    },
+};
+
+/// A kind of warning that can be turned on or off with `-W` and `-A`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum Lint {
+    /// A local variable or parameter that is never read.
+    UnusedVariables,
+    /// A local variable with the same name as one in an enclosing scope.
+    ShadowedVariables,
+    /// Statements after a `return`.
+    UnreachableCode,
+    /// An assignment to a global variable that isn't declared anywhere in the script.
+    UndeclaredAssignments,
+    /// A read of a global variable that isn't defined anywhere in the script.
+    UndefinedGlobals,
+}
+
+impl Lint {
+    const ALL: [Lint; 5] = [Lint::UnusedVariables, Lint::ShadowedVariables, Lint::UnreachableCode, Lint::UndeclaredAssignments, Lint::UndefinedGlobals];
+
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            Lint::UnusedVariables => "unused_variables",
+            Lint::ShadowedVariables => "shadowed_variables",
+            Lint::UnreachableCode => "unreachable_code",
+            Lint::UndeclaredAssignments => "undeclared_assignments",
+            Lint::UndefinedGlobals => "undefined_globals",
+        }
+    }
+}
+
+/// The argument of `-W` or `-A`: a lint name, or `warnings` for all lints.
+pub(crate) struct LintGroup(Vec<Lint>);
+
+impl FromStr for LintGroup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<LintGroup, String> {
+        if s == "warnings" { return Ok(LintGroup(Lint::ALL.to_vec())) }
+        Lint::ALL.iter().copied()
+            .find(|lint| lint.name() == s)
+            .map(|lint| LintGroup(vec![lint]))
+            .ok_or_else(|| format!("unknown lint {:?}, expected warnings, {}", s, Lint::ALL.iter().map(Lint::name).collect::<Vec<_>>().join(", ")))
+    }
+}
+
+/// The lints that are reported.
+pub(crate) struct Lints(HashSet<Lint>);
+
+impl Lints {
+    /// All lints are on by default. Lints given to `-W` take precedence over those given to `-A`, so `-A warnings -W unused_variables` only shows unused variables.
+    pub(crate) fn new(warn: &[LintGroup], allow: &[LintGroup]) -> Lints {
+        let mut enabled = Lint::ALL.iter().copied().collect::<HashSet<_>>();
+        for LintGroup(lints) in allow {
+            for lint in lints { enabled.remove(lint); }
+        }
+        for LintGroup(lints) in warn {
+            enabled.extend(lints.iter().copied());
+        }
+        Lints(enabled)
+    }
+}
+
+pub(crate) struct Warning {
+    pub(crate) lint: Lint,
+    pub(crate) msg: String,
+    pub(crate) span: Span,
+}
+
+struct Local<'a> {
+    name: &'a str,
+    span: Span,
+    is_param: bool,
+    is_function: bool,
+    used: bool,
+}
+
+struct Resolver<'a> {
+    lints: &'a Lints,
+    /// The global variables declared anywhere in the script, as well as those already defined in the VM.
+    globals: HashSet<&'a str>,
+    /// The local variables of each scope, innermost last. Empty in top-level code.
+    scopes: Vec<Vec<Local<'a>>>,
+    /// The index in `scopes` of the outermost scope of the function being resolved.
+    function_scope: usize,
+    warnings: Vec<Warning>,
+}
+
+impl<'a> Resolver<'a> {
+    fn warn(&mut self, lint: Lint, span: Span, msg: String) {
+        if self.lints.0.contains(&lint) {
+            self.warnings.push(Warning { lint, msg, span });
+        }
+    }
+
+    fn stmts(&mut self, stmts: &'a [Stmt]) {
+        if let Some(idx) = stmts.iter().position(diverges) {
+            if let (Some(first), Some(last)) = (stmts.get(idx + 1), stmts.last()) {
+                self.warn(Lint::UnreachableCode, Span::new(first.span().start, last.span().end), format!("Unreachable code."));
+            }
+        }
+        for stmt in stmts {
+            self.stmt(stmt);
+        }
+    }
+
+    fn stmt(&mut self, stmt: &'a Stmt) {
+        match stmt {
+            Stmt::Fun { name, name_span, params, body, .. } => {
+                self.declare(name, *name_span, false, true);
+                let enclosing_function_scope = self.function_scope;
+                self.function_scope = self.scopes.len();
+                self.scopes.push(Vec::default());
+                for (param_span, param) in params {
+                    self.declare(param, *param_span, true, false);
+                }
+                self.stmts(body);
+                self.end_scope();
+                self.function_scope = enclosing_function_scope;
+            }
+            Stmt::Var { name, name_span, init, .. } => {
+                if let Some(init) = init { self.expr(init) }
+                self.declare(name, *name_span, false, false);
+            }
+            Stmt::Expr { expr, .. } | Stmt::Print { expr, .. } => self.expr(expr),
+            Stmt::If { cond, then, else_, .. } => {
+                self.expr(cond);
+                self.stmt(then);
+                if let Some(else_) = else_ { self.stmt(else_) }
+            }
+            Stmt::Return { expr, .. } => if let Some(expr) = expr { self.expr(expr) },
+            Stmt::While { cond, body, .. } => {
+                self.expr(cond);
+                self.stmt(body);
+            }
+            Stmt::Block { stmts, .. } => {
+                self.scopes.push(Vec::default());
+                self.stmts(stmts);
+                self.end_scope();
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &'a Expr) {
+        match expr {
+            Expr::Assign { rcpt: Some(rcpt), value, .. } => {
+                self.expr(rcpt);
+                self.expr(value);
+            }
+            Expr::Assign { rcpt: None, name, name_span, value, .. } => {
+                self.expr(value);
+                if self.resolve(name).is_none() && !self.globals.contains(&**name) {
+                    self.warn(Lint::UndeclaredAssignments, *name_span, format!("Assignment to undeclared global variable '{}'.", name));
+                }
+            }
+            Expr::Binary { lhs, rhs, .. } => {
+                self.expr(lhs);
+                self.expr(rhs);
+            }
+            Expr::Unary { inner, .. } => self.expr(inner),
+            Expr::Call { rcpt, args, .. } => {
+                self.expr(rcpt);
+                for arg in args {
+                    self.expr(arg);
+                }
+            }
+            Expr::True { .. } | Expr::False { .. } | Expr::Nil { .. } | Expr::Number { .. } | Expr::String { .. } => {}
+            Expr::Variable { name, span } => if let Some(local) = self.resolve(name) {
+                local.used = true;
+            } else if !self.globals.contains(&**name) {
+                self.warn(Lint::UndefinedGlobals, *span, format!("Global variable '{}' is never defined.", name));
+            },
+        }
+    }
+
+    /// Adds a local variable to the innermost scope. Does nothing in top-level code, since globals are collected beforehand.
+    fn declare(&mut self, name: &'a str, span: Span, is_param: bool, is_function: bool) {
+        let (scope, outer) = if let Some(scopes) = self.scopes.split_last() { scopes } else { return };
+        if scope.iter().any(|local| local.name == name) { return } // reported by the compiler
+        if let Some(shadowed) = outer.iter().rev().flat_map(|scope| scope.iter().rev()).find(|local| local.name == name) {
+            let msg = format!("Variable '{}' shadows the one declared on line {}.", name, shadowed.span.start.line);
+            self.warn(Lint::ShadowedVariables, span, msg);
+        }
+        self.scopes.last_mut().expect("checked above").push(Local { name, span, is_param, is_function, used: false });
+    }
+
+    fn end_scope(&mut self) {
+        for local in self.scopes.pop().expect("no scope to end") {
+            if !local.used && !local.name.starts_with('_') {
+                let kind = if local.is_param { "parameter" } else if local.is_function { "function" } else { "variable" };
+                self.warn(Lint::UnusedVariables, local.span, format!("Unused {} '{}'.", kind, local.name));
+            }
+        }
+    }
+
+    /// Finds the local variable of the current function a name refers to. Since the compiler doesn't support closures yet, locals of enclosing functions are accessed as globals.
+    fn resolve(&mut self, name: &str) -> Option<&mut Local<'a>> {
+        self.scopes[self.function_scope..].iter_mut().rev().flat_map(|scope| scope.iter_mut().rev()).find(|local| local.name == name)
+    }
+}
+
+/// Whether the statements after this one in the same block are never run.
+fn diverges(stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::Return { .. } => true,
+        Stmt::Block { stmts, .. } => stmts.iter().any(diverges),
+        Stmt::If { then, else_: Some(else_), .. } => diverges(then) && diverges(else_),
+        _ => false,
+    }
+}
+
+/// Checks a script for likely mistakes. `defined_globals` are the globals that already exist in the VM, e.g. native functions or variables from earlier repl inputs.
+pub(crate) fn resolve<'a>(stmts: &'a [Stmt], defined_globals: impl IntoIterator<Item = &'a str>, lints: &'a Lints) -> Vec<Warning> {
+    let mut globals = defined_globals.into_iter().collect::<HashSet<_>>();
+    globals.extend(stmts.iter().filter_map(|stmt| match stmt {
+        Stmt::Fun { name, .. } | Stmt::Var { name, .. } => Some(&**name),
+        _ => None,
+    }));
+    let mut resolver = Resolver {
+        lints,
+        globals,
+        scopes: Vec::default(),
+        function_scope: 0,
+        warnings: Vec::default(),
+    };
+    resolver.stmts(stmts);
+    let mut warnings = resolver.warnings;
+    warnings.sort_by_key(|warning| warning.span.start.offset);
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lints(warn: &[&str], allow: &[&str]) -> Lints {
+        let groups = |names: &[&str]| names.iter().map(|name| name.parse().expect("unknown lint")).collect::<Vec<_>>();
+        Lints::new(&groups(warn), &groups(allow))
+    }
+
+    /// Resolves a script in a VM where only `clock` is defined, and returns its warnings.
+    fn warnings_with(source: &str, lints: &Lints) -> Vec<(Lint, String)> {
+        let stmts = crate::parse(source.as_bytes()).unwrap_or_else(|e| panic!("failed to parse: {}", e));
+        resolve(&stmts, vec!["clock"], lints).into_iter().map(|warning| (warning.lint, warning.msg)).collect()
+    }
+
+    fn warnings(source: &str) -> Vec<(Lint, String)> {
+        warnings_with(source, &lints(&[], &[]))
+    }
+
+    fn warning(lint: Lint, msg: &str) -> Vec<(Lint, String)> {
+        vec![(lint, msg.to_owned())]
+    }
+
+    #[test]
+    fn unused_variables() {
+        assert_eq!(warnings("fun f() { var a = 1; }"), warning(Lint::UnusedVariables, "Unused variable 'a'."));
+        assert_eq!(warnings("fun f(x) {}"), warning(Lint::UnusedVariables, "Unused parameter 'x'."));
+        assert_eq!(warnings("fun f() { fun g() {} }"), warning(Lint::UnusedVariables, "Unused function 'g'."));
+        assert_eq!(warnings("fun f(_x) { var a = 1; fun g() { return a; } print a; return g; }"), warning(Lint::UndefinedGlobals, "Global variable 'a' is never defined."));
+        assert_eq!(warnings("fun f(_x) { var a = 1; fun g() { return 1; } print a; return g; }"), vec![]);
+        assert_eq!(warnings("var unused = 1;"), vec![]); // globals may be used by later repl inputs
+    }
+
+    #[test]
+    fn shadowed_variables() {
+        assert_eq!(warnings("{\nvar a = 1;\n{ var a = 2; print a; }\nprint a;\n}"), warning(Lint::ShadowedVariables, "Variable 'a' shadows the one declared on line 2."));
+        assert_eq!(warnings("{ var a = 1; print a; } { var a = 2; print a; }"), vec![]);
+    }
+
+    #[test]
+    fn unreachable_code() {
+        assert_eq!(warnings("fun f() { return 1; print 2; }"), warning(Lint::UnreachableCode, "Unreachable code."));
+        assert_eq!(warnings("fun f(x) { if (x) return 1; print 2; }"), vec![]);
+    }
+
+    #[test]
+    fn undeclared_assignments() {
+        assert_eq!(warnings("x = 1;"), warning(Lint::UndeclaredAssignments, "Assignment to undeclared global variable 'x'."));
+        assert_eq!(warnings("fun f() { x = 1; } var x;"), vec![]);
+        assert_eq!(warnings("fun f() { var x; fun g() { x = 1; } g(); print x; }"), warning(Lint::UndeclaredAssignments, "Assignment to undeclared global variable 'x'."));
+    }
+
+    #[test]
+    fn undefined_globals() {
+        assert_eq!(warnings("print x;"), warning(Lint::UndefinedGlobals, "Global variable 'x' is never defined."));
+        assert_eq!(warnings("fun f() { return g() + clock(); } fun g() { return 1; }"), vec![]);
+        // the compiler has no upvalues, so locals of enclosing functions are read as globals
+        assert_eq!(warnings("fun f() { var a = 1; fun g() { return a; } g(); print a; }"), warning(Lint::UndefinedGlobals, "Global variable 'a' is never defined."));
+        assert_eq!(warnings("{ var a = 1; fun f() { return a; } f(); print a; }"), warning(Lint::UndefinedGlobals, "Global variable 'a' is never defined."));
+        assert_eq!(warnings("var a = 1; fun f() { var a = 2; fun g() { return a; } g(); print a; }"), vec![]);
+    }
+
+    #[test]
+    fn lint_levels() {
+        let source = "print x; fun f() { var a; }";
+        assert_eq!(warnings_with(source, &lints(&["unused_variables"], &["warnings"])), warning(Lint::UnusedVariables, "Unused variable 'a'."));
+        assert_eq!(warnings_with(source, &lints(&[], &["warnings"])), vec![]);
+        assert_eq!(warnings_with(source, &lints(&[], &["unused_variables"])), warning(Lint::UndefinedGlobals, "Global variable 'x' is never defined."));
+    }
+}
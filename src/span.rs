@@ -0,0 +1,50 @@
+use std::fmt;
+
+/// A line and column in a source file, both starting at 1. Columns count characters, not bytes. A column of 0 means the column is unknown, e.g. in hand-written bytecode assembly.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Position {
+    pub(crate) line: u32,
+    pub(crate) column: u32,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.column == 0 {
+            write!(f, "{}", self.line)
+        } else {
+            write!(f, "{}:{}", self.line, self.column)
+        }
+    }
+}
+
+/// A position in a source file along with its byte offset.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Location {
+    pub(crate) offset: usize,
+    pub(crate) line: u32,
+    pub(crate) column: u32,
+}
+
+impl From<Location> for Position {
+    fn from(Location { line, column, .. }: Location) -> Position {
+        Position { line, column }
+    }
+}
+
+/// The part of a source file from `start` up to but not including `end`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Span {
+    pub(crate) start: Location,
+    pub(crate) end: Location,
+}
+
+impl Span {
+    pub(crate) fn new(start: Location, end: Location) -> Span {
+        Span { start, end }
+    }
+
+    /// A span of length 0 at the given location, e.g. where an omitted piece of code would be.
+    pub(crate) fn empty(location: Location) -> Span {
+        Span { start: location, end: location }
+    }
+}
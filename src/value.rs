@@ -6,6 +6,7 @@ use {
             self,
             prelude::*,
         },
+        mem,
     },
     byteorder::{
         LittleEndian,
@@ -24,11 +25,25 @@ use {
             Error,
             Result,
         },
+        heap::Allocation,
+        intern::{
+            InternedString,
+            Interner,
+        },
+        span::Position,
         vm::OpCode,
     },
 };
 
-#[derive(From, Trace, Finalize)]
+/// Follows the magic byte in bytecode files, so files written before the header was added can be told apart.
+const SIGNATURE: &[u8; 4] = b"rlox";
+/// Must be incremented whenever the format written by `FunctionInner::write` or `Value::write` changes.
+pub(crate) const FORMAT_VERSION: u16 = 5;
+/// Bit flags for optional format features used by the file. Files using features not listed here are rejected.
+const FEATURES: u32 = 0;
+
+/// A Lox value. Nil, booleans, numbers, and native functions are stored inline, other values are pointers to the heap.
+#[derive(Clone, From, Trace, Finalize)]
 pub(crate) enum Value {
     Nil,
     Bool(bool),
@@ -36,12 +51,12 @@ pub(crate) enum Value {
     Closure(Gc<Closure>),
     Function(Function),
     NativeFn(NativeFn),
-    String(Gc<String>),
+    String(#[unsafe_ignore_trace] InternedString),
 }
 
 impl Value {
-    pub(crate) fn new(value: impl Into<Value>) -> Gc<Value> { Gc::new(value.into()) }
-    pub(crate) fn nil() -> Gc<Value> { Gc::new(Value::Nil) }
+    pub(crate) fn new(value: impl Into<Value>) -> Value { value.into() }
+    pub(crate) fn nil() -> Value { Value::Nil }
 
     pub(crate) fn as_bool(&self) -> bool {
         match *self {
@@ -53,23 +68,20 @@ impl Value {
 
     pub(crate) fn as_function(&self) -> Option<Function> { if let Value::Function(f) = self { Some(f.clone()) } else { None } }
     pub(crate) fn as_number(&self) -> Option<f64> { if let Value::Number(n) = *self { Some(n) } else { None } }
-    pub(crate) fn as_string(&self) -> Option<Gc<String>> { if let Value::String(s) = self { Some(s.clone()) } else { None } }
 
-    fn read(stream: &mut impl Read) -> Result<Value> {
+    /// The number of bytes accounted for a string of the given length.
+    pub(crate) fn string_size(len: usize) -> usize { mem::size_of::<Value>() + len }
+
+    fn read(stream: &mut impl Read, strings: &Interner) -> Result<Value> {
         Ok(match stream.read_u8()? {
             0 => Value::Nil,
             1 => Value::Bool(false),
             2 => Value::Bool(true),
             3 => Value::Number(stream.read_f64::<LittleEndian>()?),
-            4 => Value::Closure(Gc::new(Closure::read(stream)?)),
-            5 => Value::Function(FunctionInner::read(stream, false)?.wrap()),
+            4 => Value::Closure(Gc::new(Closure::read(stream, strings)?)),
+            5 => Value::Function(FunctionInner::read(stream, false, strings)?.wrap()),
             6 => Value::NativeFn(NativeFn { inner: crate::native::deserialize(stream.read_u8()?).ok_or_else(|| Error::Decode("NativeFn"))? }),
-            7 => {
-                let len = stream.read_u64::<LittleEndian>()?.try_into().map_err(|_| Error::Decode("String"))?;
-                let mut buf = Vec::with_capacity(len);
-                stream.read_exact(&mut buf)?;
-                Value::String(Gc::new(String::from_utf8(buf).map_err(|_| Error::Decode("String"))?))
-            }
+            7 => Value::String(strings.intern(String::from_utf8(read_bytes(stream, "String")?).map_err(|_| Error::Decode("String"))?)), // interned like strings created by the compiler
             _ => return Err(Error::Decode("Value")),
         })
     }
@@ -111,12 +123,6 @@ impl From<crate::native::NativeFn> for Value {
     }
 }
 
-impl From<String> for Value {
-    fn from(s: String) -> Value {
-        Value::String(Gc::new(s))
-    }
-}
-
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -132,6 +138,19 @@ impl fmt::Display for Value {
     }
 }
 
+/// Formats the value using the syntax of the bytecode assembler.
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Nil | Value::Bool(_) | Value::Number(_) => fmt::Display::fmt(self, f),
+            Value::Closure(closure) => write!(f, "<closure {}>", closure),
+            Value::Function(function) => write!(f, "<fn {}>", function.borrow()),
+            Value::NativeFn(NativeFn { inner }) => write!(f, "<native fn {}>", crate::native::name(*inner)),
+            Value::String(s) => write!(f, "{:?}", s),
+        }
+    }
+}
+
 impl PartialEq for Value {
     fn eq(&self, rhs: &Value) -> bool {
         match (self, rhs) {
@@ -139,31 +158,49 @@ impl PartialEq for Value {
             (Value::Bool(lhs), Value::Bool(rhs)) => lhs == rhs,
             (Value::Number(lhs), Value::Number(rhs)) => lhs == rhs,
             (Value::Closure(lhs), Value::Closure(rhs)) => Gc::ptr_eq(lhs, rhs),
-            (Value::String(lhs), Value::String(rhs)) => lhs == rhs, //TODO adjust for interning
+            (Value::String(lhs), Value::String(rhs)) => lhs == rhs, // compares pointers since strings are interned
             //TODO other kinds of objects
             (_, _) => false, // values of different types are never equal
         }
     }
 }
 
+/// Reads a length-prefixed byte string without trusting the length for preallocation.
+fn read_bytes(stream: &mut impl Read, ty: &'static str) -> Result<Vec<u8>> {
+    let len = stream.read_u64::<LittleEndian>()?;
+    let mut buf = Vec::default();
+    stream.by_ref().take(len).read_to_end(&mut buf)?;
+    if buf.len() as u64 != len { return Err(Error::Decode(ty)) }
+    Ok(buf)
+}
+
 #[derive(Trace, Finalize)]
 pub(crate) struct Closure {
     pub(crate) function: Function,
+    #[unsafe_ignore_trace]
+    _allocation: Allocation,
 }
 
 impl Closure {
+    /// The number of bytes accounted for a closure.
+    pub(crate) const SIZE: usize = mem::size_of::<Closure>();
+
     pub(crate) fn new(function: Function) -> Gc<Closure> {
-        Gc::new(Closure { function })
+        Gc::new(Closure {
+            function,
+            _allocation: Allocation::new(Closure::SIZE),
+        })
     }
 
-    fn read(stream: &mut impl Read) -> Result<Closure> {
+    fn read(stream: &mut impl Read, strings: &Interner) -> Result<Closure> {
         Ok(Closure {
-            function: FunctionInner::read(stream, false)?.wrap(),
+            function: FunctionInner::read(stream, false, strings)?.wrap(),
+            _allocation: Allocation::new(Closure::SIZE),
         })
     }
 
     fn write(&self, sink: &mut impl Write) -> io::Result<()> {
-        let Closure { function } = self;
+        let Closure { function, .. } = self;
         function.borrow().write(sink)?;
         Ok(())
     }
@@ -179,8 +216,9 @@ impl fmt::Display for Closure {
 pub(crate) struct FunctionInner {
     pub(crate) arity: u8,
     pub(crate) chunk: Vec<u8>,
-    pub(crate) lines: Vec<u32>,
-    pub(crate) constants: Vec<Gc<Value>>,
+    #[unsafe_ignore_trace]
+    pub(crate) lines: Vec<Position>,
+    pub(crate) constants: Vec<Value>,
     pub(crate) name: Option<Gc<String>>,
 }
 
@@ -189,47 +227,91 @@ impl FunctionInner {
         Gc::new(GcCell::new(self))
     }
 
-    pub(crate) fn add_code(&mut self, line: u32, code: u8) {
-        self.lines.push(line);
+    pub(crate) fn add_code(&mut self, position: Position, code: u8) {
+        self.lines.push(position);
         self.chunk.push(code);
     }
 
-    pub(crate) fn add_constant(&mut self, value: Gc<Value>) -> usize {
+    pub(crate) fn add_constant(&mut self, value: Value) -> usize {
         self.constants.push(value);
         self.constants.len() - 1
     }
 
-    pub(crate) fn read(stream: &mut impl Read, is_script: bool) -> Result<FunctionInner> {
+    /// Reads a bytecode file in the format written by `write_bytecode`, starting after the magic byte.
+    ///
+    /// Returns the script function and the names of the global slots it was compiled with, see `GlobalTable::link`.
+    pub(crate) fn read_bytecode(stream: &mut impl Read, strings: &Interner) -> Result<(FunctionInner, Vec<InternedString>)> {
+        let mut signature = [0; 4];
+        if stream.read_exact(&mut signature).is_err() || signature != *SIGNATURE {
+            return Err(Error::BytecodeVersion { format_version: None, rlox_version: None }) // written before the header was introduced
+        }
+        let format_version = stream.read_u16::<LittleEndian>()?;
+        let rlox_version = String::from_utf8(read_bytes(stream, "header")?).map_err(|_| Error::Decode("header"))?;
+        if format_version != FORMAT_VERSION {
+            return Err(Error::BytecodeVersion { format_version: Some(format_version), rlox_version: Some(rlox_version) })
+        }
+        let features = stream.read_u32::<LittleEndian>()?;
+        if features & !FEATURES != 0 { return Err(Error::BytecodeFeatures(features & !FEATURES)) }
+        let checksum = stream.read_u32::<LittleEndian>()?;
+        let mut body = Vec::default();
+        stream.read_to_end(&mut body)?;
+        if crc32fast::hash(&body) != checksum { return Err(Error::Checksum) }
+        let mut body = &*body;
+        let num_globals = body.read_u32::<LittleEndian>()?;
+        let mut globals = Vec::default();
+        for _ in 0..num_globals {
+            globals.push(strings.intern(String::from_utf8(read_bytes(&mut body, "global name")?).map_err(|_| Error::Decode("global name"))?));
+        }
+        let function = FunctionInner::read(&mut body, true, strings)?;
+        if !body.is_empty() { return Err(Error::Decode("trailing data")) }
+        crate::verifier::verify(&function, globals.len())?;
+        Ok((function, globals))
+    }
+
+    /// Writes this function as a bytecode file, including the magic byte, the versioned header, and the names of the global slots it uses.
+    pub(crate) fn write_bytecode(&self, globals: &[InternedString], sink: &mut impl Write) -> io::Result<()> {
+        let mut body = Vec::default();
+        body.write_u32::<LittleEndian>(globals.len().try_into().expect("more than u32::MAX globals"))?;
+        for name in globals {
+            body.write_u64::<LittleEndian>(name.len().try_into().expect("global name is longer than u64::MAX bytes"))?;
+            body.write_all(name.as_bytes())?;
+        }
+        self.write(&mut body)?;
+        sink.write_u8(0xc0)?; // magic byte to distinguish rlox bytecode from Lox source code
+        sink.write_all(SIGNATURE)?;
+        sink.write_u16::<LittleEndian>(FORMAT_VERSION)?;
+        let rlox_version = env!("CARGO_PKG_VERSION");
+        sink.write_u64::<LittleEndian>(rlox_version.len().try_into().expect("version is longer than u64::MAX bytes"))?;
+        sink.write_all(rlox_version.as_bytes())?;
+        sink.write_u32::<LittleEndian>(FEATURES)?;
+        sink.write_u32::<LittleEndian>(crc32fast::hash(&body))?;
+        sink.write_all(&body)
+    }
+
+    /// Reads a function in the format written by `write`. The bytecode must be checked using `verifier::verify` before it's run.
+    pub(crate) fn read(stream: &mut impl Read, is_script: bool, strings: &Interner) -> Result<FunctionInner> {
         Ok(FunctionInner {
             name: if is_script { None } else {
-                let len = stream.read_u64::<LittleEndian>()?.try_into().map_err(|_| Error::Decode("String"))?;
-                let mut buf = Vec::with_capacity(len);
-                stream.read_exact(&mut buf)?;
-                Some(Gc::new(String::from_utf8(buf).map_err(|_| Error::Decode("String"))?))
+                Some(Gc::new(String::from_utf8(read_bytes(stream, "String")?).map_err(|_| Error::Decode("String"))?))
             },
             arity: if is_script { 0 } else { stream.read_u8()? },
             constants: {
-                let len = stream.read_u8()?.into();
-                let mut constants = Vec::with_capacity(len);
+                let len = stream.read_u32::<LittleEndian>()?;
+                let mut constants = Vec::default(); // not preallocated since the length hasn't been checked against the file size
                 for _ in 0..len {
-                    constants.push(Gc::new(Value::read(stream)?));
+                    constants.push(Value::read(stream, strings)?);
                 }
                 constants
             },
-            chunk: {
-                let len = stream.read_u64::<LittleEndian>()?.try_into().map_err(|_| Error::Decode("Chunk"))?;
-                let mut chunk = vec![0; len];
-                stream.read_exact(&mut chunk)?;
-                //TODO validate chunk for safety (and maybe offer an unsafe more where none of the other parts of a .rlox file are validated either)
-                chunk
-            },
+            chunk: read_bytes(stream, "Chunk")?,
             lines: {
                 let mut lines = Vec::default();
                 loop {
                     let run_len = stream.read_u8()?;
                     if run_len == 0 { break }
                     let line = stream.read_u32::<LittleEndian>()?;
-                    lines.resize(lines.len() + usize::from(run_len), line);
+                    let column = stream.read_u32::<LittleEndian>()?;
+                    lines.resize(lines.len() + usize::from(run_len), Position { line, column });
                 }
                 lines
             },
@@ -243,35 +325,128 @@ impl FunctionInner {
             sink.write_all(name.as_bytes())?;
             sink.write_u8(*arity)?;
         } else {
-            sink.write_u8(0xc0)?; // magic byte to distinguish rlox bytecode from Lox source code
             assert_eq!(*arity, 0);
         }
-        sink.write_u8(constants.len().try_into().expect("more than u8::MAX constants"))?;
+        sink.write_u32::<LittleEndian>(constants.len().try_into().expect("more than u32::MAX constants"))?;
         for constant in constants {
             constant.write(sink)?;
         }
         sink.write_u64::<LittleEndian>(chunk.len().try_into().expect("bytecode is longer than u64::MAX bytes"))?;
         sink.write_all(&chunk)?;
         let mut lines = lines.iter().peekable();
-        while let Some(&line) = lines.next() {
+        while let Some(&position) = lines.next() {
             let mut run_len = 1;
-            while run_len < u8::MAX && lines.peek().map_or(false, |&&next_line| next_line == line) {
+            while run_len < u8::MAX && lines.peek().map_or(false, |&&next_position| next_position == position) {
                 run_len += 1;
                 let _ = lines.next();
             }
             sink.write_u8(run_len)?;
-            sink.write_u32::<LittleEndian>(line)?;
+            sink.write_u32::<LittleEndian>(position.line)?;
+            sink.write_u32::<LittleEndian>(position.column)?;
         }
         sink.write_u8(0)?; // end of lines
         Ok(())
     }
 
-    pub(crate) fn disassemble(&self) {
-        println!("== {} ==", self);
-        let mut rest = &*self.chunk;
-        while !rest.is_empty() {
-            OpCode::disassemble(&mut rest, &self.constants);
+    /// Re-encodes the chunk with each global slot operand replaced by `map_global(slot)`, using the shortest encoding of every instruction.
+    ///
+    /// Shortening a jump can only make other jumps shorter, so jumps start out without a `Wide` prefix and are widened until all offsets fit.
+    pub(crate) fn reencode(&mut self, map_global: impl Fn(usize) -> usize) {
+        let chunk = mem::take(&mut self.chunk);
+        let lines = mem::take(&mut self.lines);
+        let mut instructions = Vec::default();
+        let mut offset = 0;
+        while offset < chunk.len() {
+            let mut instruction = OpCode::decode(&chunk, offset).expect("invalid bytecode");
+            if instruction.opcode.is_global() { instruction.operand = map_global(instruction.operand) }
+            instructions.push((offset, instruction));
+            offset += instruction.len;
+        }
+        // for each jump, the index of the instruction it jumps to (`instructions.len()` for the end of the chunk)
+        let targets = instructions.iter().map(|&(offset, instruction)| instruction.jump_target(offset).map(|target| {
+            instructions.binary_search_by_key(&target, |&(offset, _)| offset).unwrap_or_else(|idx| {
+                assert_eq!(idx, instructions.len(), "jump target is not the start of an instruction");
+                idx
+            })
+        })).collect::<Vec<_>>();
+        let mut wide = instructions.iter().zip(&targets).map(|(&(_, instruction), target)| target.is_none() && instruction.operand > usize::from(u8::MAX)).collect::<Vec<_>>();
+        let mut new_offsets = Vec::with_capacity(instructions.len() + 1);
+        let mut scratch = Vec::default();
+        let jump_len = |new_offsets: &[usize], idx: usize, target: usize| {
+            let next = new_offsets[idx + 1];
+            if let OpCode::Loop = instructions[idx].1.opcode { next - new_offsets[target] } else { new_offsets[target] - next }
+        };
+        loop {
+            new_offsets.clear();
+            let mut new_offset = 0;
+            for (&(_, instruction), &wide) in instructions.iter().zip(&wide) {
+                new_offsets.push(new_offset);
+                scratch.clear();
+                new_offset += instruction.opcode.encode(0, wide, &mut scratch);
+            }
+            new_offsets.push(new_offset);
+            let mut changed = false;
+            for (idx, target) in targets.iter().enumerate() {
+                if let (Some(target), false) = (*target, wide[idx]) {
+                    if jump_len(&new_offsets, idx, target) > usize::from(u16::MAX) {
+                        wide[idx] = true;
+                        changed = true;
+                    }
+                }
+            }
+            if !changed { break }
+        }
+        for (idx, &(offset, instruction)) in instructions.iter().enumerate() {
+            let operand = if let Some(target) = targets[idx] { jump_len(&new_offsets, idx, target) } else { instruction.operand };
+            let len = instruction.opcode.encode(operand, wide[idx], &mut self.chunk);
+            self.lines.resize(self.lines.len() + len, lines[offset]);
+        }
+    }
+
+    /// Prints the disassembled bytecode of this function, followed by that of any functions defined in it.
+    ///
+    /// The output can be turned back into bytecode using `--assemble`.
+    ///
+    /// `globals` are the names of the global slots, those of the VM for compiled bytecode. They're listed before the instructions of the top-level script.
+    pub(crate) fn disassemble(&self, globals: &[InternedString]) {
+        self.write_disassembly(globals, &mut io::stdout().lock()).expect("failed to print disassembly");
+    }
+
+    /// Like `disassemble`, but writes to the given sink instead of stdout.
+    pub(crate) fn write_disassembly(&self, globals: &[InternedString], out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "== {} ==", self)?;
+        if self.name.is_some() {
+            writeln!(out, "arity {}", self.arity)?;
+        } else {
+            for (slot, name) in globals.iter().enumerate() {
+                writeln!(out, "global 0x{:02x} ({})", slot, name)?;
+            }
+        }
+        let mut referenced = vec![false; self.constants.len()];
+        let mut offset = 0;
+        while offset < self.chunk.len() {
+            if let Some(const_idx) = OpCode::constant_operand(self, offset) { referenced[const_idx] = true }
+            offset = OpCode::disassemble(self, globals, offset, out)?;
+        }
+        for (const_idx, constant) in self.constants.iter().enumerate() {
+            if !referenced[const_idx] {
+                writeln!(out, "constant 0x{:02x} ({:?})", const_idx, constant)?;
+            }
         }
+        for constant in &self.constants {
+            match *constant {
+                Value::Closure(ref closure) => {
+                    writeln!(out)?;
+                    closure.function.borrow().write_disassembly(globals, out)?;
+                }
+                Value::Function(ref function) => {
+                    writeln!(out)?;
+                    function.borrow().write_disassembly(globals, out)?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
     }
 }
 
@@ -287,7 +462,7 @@ impl fmt::Display for FunctionInner {
 
 pub(crate) type Function = Gc<GcCell<FunctionInner>>;
 
-#[derive(Trace, Finalize)]
+#[derive(Clone, Trace, Finalize)]
 pub(crate) struct NativeFn {
     #[unsafe_ignore_trace]
     pub(crate) inner: crate::native::NativeFn,
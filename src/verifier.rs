@@ -0,0 +1,253 @@
+//! Checks that bytecode loaded from a file can't make the VM misbehave, since `Vm::run` trusts it.
+
+use crate::{
+    error::{
+        Error,
+        Result,
+    },
+    value::{
+        FunctionInner,
+        Value,
+    },
+    vm::{
+        Instruction,
+        OpCode,
+    },
+};
+
+/// Checks every instruction of the given function and the functions defined in it. `num_globals` is the number of global slots the bytecode was compiled with.
+pub(crate) fn verify(function: &FunctionInner, num_globals: usize) -> Result {
+    macro_rules! error {
+        ($offset:expr, $($arg:tt)*) => {{
+            return Err(Error::Verify {
+                function: function.to_string(),
+                offset: $offset,
+                msg: format!($($arg)*),
+            })
+        }};
+    }
+
+    let FunctionInner { arity, chunk, lines, constants, .. } = function;
+    if lines.len() != chunk.len() { error!(lines.len().min(chunk.len()), "line table has {} entries but the chunk has {} bytes", lines.len(), chunk.len()) }
+    // decode instructions in order to find the instruction boundaries
+    let mut decoded = vec![None; chunk.len()];
+    let mut offset = 0;
+    while offset < chunk.len() {
+        let instruction = match OpCode::decode(chunk, offset) {
+            Ok(instruction) => instruction,
+            Err(msg) => error!(offset, "{}", msg),
+        };
+        decoded[offset] = Some(instruction);
+        offset += instruction.len;
+    }
+    // follow all paths through the function, tracking the height of the value stack in the call frame (including the called closure in slot 0)
+    let mut heights = vec![None; chunk.len()];
+    let mut worklist = vec![(0, usize::from(*arity) + 1)];
+    while let Some((offset, height)) = worklist.pop() {
+        if offset >= chunk.len() { error!(offset, "execution can run past the end of the chunk") }
+        match heights[offset] {
+            Some(prev_height) if prev_height == height => continue,
+            Some(prev_height) => error!(offset, "stack height is {} or {} depending on the path taken", prev_height, height),
+            None => heights[offset] = Some(height),
+        }
+        let instruction = decoded[offset].expect("jump targets are checked before being added to the worklist");
+        let Instruction { opcode, operand, len, .. } = instruction;
+        macro_rules! pop {
+            ($n:expr) => {{
+                let n = $n;
+                if height < n + 1 { error!(offset, "{:?} pops {} values but the stack only has {} besides the function", opcode, n, height - 1) }
+                height - n
+            }};
+        }
+        macro_rules! constant {
+            ($pat:pat, $expected:expr) => {{
+                match constants.get(operand) {
+                    Some(constant) => if !matches!(*constant, $pat) { error!(offset, "{:?} expects a {} constant but constant 0x{:02x} is {:?}", opcode, $expected, operand, constant) },
+                    None => error!(offset, "constant index 0x{:02x} is out of range, the function has {} constants", operand, constants.len()),
+                }
+            }};
+        }
+        macro_rules! global {
+            () => {{
+                if operand >= num_globals { error!(offset, "global slot 0x{:02x} is out of range, there are {} globals", operand, num_globals) }
+            }};
+        }
+        macro_rules! local {
+            () => {{
+                if operand >= height { error!(offset, "local slot 0x{:02x} is out of range, the stack has {} values", operand, height) }
+            }};
+        }
+        let jump_target = || {
+            match instruction.jump_target(offset) {
+                Some(target) if target < chunk.len() && decoded[target].is_some() => Ok(target),
+                _ => Err(Error::Verify {
+                    function: function.to_string(),
+                    offset,
+                    msg: "jump target is not the start of an instruction".to_owned(),
+                }),
+            }
+        };
+        match opcode {
+            OpCode::Add | OpCode::Div | OpCode::Equal | OpCode::Greater | OpCode::GreaterEqual | OpCode::Less | OpCode::LessEqual | OpCode::Mul | OpCode::Sub => worklist.push((offset + len, pop!(2) + 1)),
+            OpCode::Call => worklist.push((offset + len, pop!(operand + 1) + 1)),
+            OpCode::Closure => {
+                constant!(Value::Function(_), "function");
+                worklist.push((offset + len, height + 1));
+            }
+            OpCode::Constant => {
+                constant!(_, "");
+                worklist.push((offset + len, height + 1));
+            }
+            OpCode::DefineGlobal => {
+                global!();
+                worklist.push((offset + len, pop!(1)));
+            }
+            OpCode::GetGlobal => {
+                global!();
+                worklist.push((offset + len, height + 1));
+            }
+            OpCode::SetGlobal => {
+                global!();
+                worklist.push((offset + len, pop!(1) + 1));
+            }
+            OpCode::GetLocal => {
+                local!();
+                worklist.push((offset + len, height + 1));
+            }
+            OpCode::SetLocal => {
+                local!();
+                worklist.push((offset + len, pop!(1) + 1));
+            }
+            OpCode::Echo | OpCode::Pop | OpCode::Print => worklist.push((offset + len, pop!(1))),
+            OpCode::False | OpCode::Nil | OpCode::True => worklist.push((offset + len, height + 1)),
+            OpCode::Neg | OpCode::Not => worklist.push((offset + len, pop!(1) + 1)),
+            OpCode::Jump => worklist.push((jump_target()?, height)),
+            OpCode::JumpIfFalsePeek | OpCode::JumpIfTruePeek => {
+                let height = pop!(1) + 1;
+                worklist.push((jump_target()?, height));
+                worklist.push((offset + len, height));
+            }
+            OpCode::JumpIfFalsePop => {
+                let height = pop!(1);
+                worklist.push((jump_target()?, height));
+                worklist.push((offset + len, height));
+            }
+            OpCode::Loop => worklist.push((jump_target()?, height)),
+            OpCode::Return => { let _ = pop!(1); }
+            OpCode::Wide => unreachable!("Wide prefix is decoded as part of the following instruction"),
+        }
+    }
+    for constant in constants {
+        match *constant {
+            Value::Closure(ref closure) => verify(&closure.function.borrow(), num_globals)?,
+            Value::Function(ref function) => verify(&function.borrow(), num_globals)?,
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        crate::{
+            assembler::assemble,
+            error::Error,
+            intern::Interner,
+            value::FunctionInner,
+            vm::OpCode,
+        },
+        super::verify,
+    };
+
+    /// Both branches leave the stack at the same height. The instruction offsets are relied on by the tests below.
+    const VALID: &str = "
+        == <script> ==
+        0000 1 Constant (1)
+        0002 | JumpIfFalsePop (-> else)
+        0005 | Constant (\"a\")
+        0007 | Print
+        0008 | Jump (-> end)
+        else:
+        000b | GetLocal 0x00
+        000d | Print
+        end:
+        000e | Nil
+        000f | Return
+    ";
+
+    fn function(source: &str) -> FunctionInner {
+        let (function, _) = assemble(source, &Interner::default()).unwrap_or_else(|e| panic!("failed to assemble: {}", e));
+        function
+    }
+
+    fn assert_rejected(function: &FunctionInner, expected_msg: &str) {
+        match verify(function, 0) {
+            Ok(()) => panic!("invalid bytecode was accepted"),
+            Err(Error::Verify { msg, .. }) => assert!(msg.contains(expected_msg), "unexpected verifier error: {}", msg),
+            Err(e) => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn valid() {
+        if let Err(e) = verify(&function(VALID), 0) { panic!("valid bytecode was rejected: {}", e) }
+    }
+
+    #[test]
+    fn bad_opcode() {
+        let mut function = function(VALID);
+        function.chunk[7] = OpCode::ALL.len() as u8;
+        assert_rejected(&function, "invalid opcode");
+    }
+
+    #[test]
+    fn truncated_operand() {
+        let mut function = function(VALID);
+        function.chunk.truncate(1);
+        function.lines.truncate(1);
+        assert_rejected(&function, "missing operand bytes");
+    }
+
+    #[test]
+    fn constant_out_of_range() {
+        let mut function = function(VALID);
+        function.chunk[1] = 0x10;
+        assert_rejected(&function, "constant index 0x10 is out of range");
+    }
+
+    #[test]
+    fn local_out_of_range() {
+        let mut function = function(VALID);
+        function.chunk[0xc] = 0x05;
+        assert_rejected(&function, "local slot 0x05 is out of range");
+    }
+
+    #[test]
+    fn jump_into_instruction() {
+        let mut function = function(VALID);
+        function.chunk[3] += 1; // now points at the operand of GetLocal
+        assert_rejected(&function, "jump target is not the start of an instruction");
+    }
+
+    #[test]
+    fn unbalanced_branches() {
+        let function = function("
+            == <script> ==
+            1 Constant (1)
+            JumpIfFalsePop (-> end)
+            Nil
+            end:
+            Nil
+            Return
+        ");
+        assert_rejected(&function, "depending on the path taken");
+    }
+
+    #[test]
+    fn line_table_length() {
+        let mut function = function(VALID);
+        function.lines.pop();
+        assert_rejected(&function, "line table has 15 entries but the chunk has 16 bytes");
+    }
+}
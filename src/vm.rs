@@ -1,7 +1,18 @@
 use {
     std::{
         collections::HashMap,
+        io::{
+            self,
+            prelude::*,
+        },
         mem,
+        sync::{
+            Arc,
+            atomic::{
+                AtomicBool,
+                Ordering::SeqCst,
+            },
+        },
     },
     gc::Gc,
     crate::{
@@ -9,6 +20,12 @@ use {
             Error,
             Result,
         },
+        heap,
+        intern::{
+            InternedString,
+            Interner,
+        },
+        span::Position,
         value::{
             Closure,
             FunctionInner,
@@ -27,19 +44,10 @@ macro_rules! error {
     }};
 }
 
-macro_rules! expect {
-    ($vm:expr, $option:expr, $($arg:tt)*) => {{
-        $option.ok_or_else(|| Error::Runtime {
-            msg: format!($($arg)*),
-            call_stack: $vm.frames.clone(),
-        })?
-    }};
-}
-
 const FRAMES_MAX: usize = 64;
 
 #[repr(u8)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub(crate) enum OpCode {
     Add,
     Call,
@@ -71,33 +79,226 @@ pub(crate) enum OpCode {
     SetLocal,
     Sub,
     True,
+    // opcodes added after the ones above are appended, not sorted, so their byte values stay the same
+    Echo,
+    /// Prefix which makes the operand of the following instruction 4 bytes long.
+    Wide,
+}
+
+/// What kind of operand an opcode takes.
+enum Operand {
+    None,
+    /// A single byte. Can't be widened.
+    Byte,
+    /// An index into the constants or local slots, 1 byte or 4 bytes with the `Wide` prefix.
+    Index,
+    /// A jump offset, 2 bytes or 4 bytes with the `Wide` prefix.
+    Jump,
+}
+
+/// An instruction as decoded by `OpCode::decode`.
+#[derive(Clone, Copy)]
+pub(crate) struct Instruction {
+    pub(crate) opcode: OpCode,
+    /// Whether the instruction is preceded by a `Wide` prefix.
+    pub(crate) wide: bool,
+    /// The operand, or 0 for opcodes that don't take one.
+    pub(crate) operand: usize,
+    /// The length of the instruction in bytes, including the prefix.
+    pub(crate) len: usize,
+}
+
+impl Instruction {
+    /// For jump instructions, the offset of the jump target, given the offset of this instruction.
+    pub(crate) fn jump_target(&self, offset: usize) -> Option<usize> {
+        match self.opcode {
+            OpCode::Jump | OpCode::JumpIfFalsePeek | OpCode::JumpIfFalsePop | OpCode::JumpIfTruePeek => (offset + self.len).checked_add(self.operand),
+            OpCode::Loop => (offset + self.len).checked_sub(self.operand),
+            _ => None,
+        }
+    }
 }
 
 impl OpCode {
-    pub(crate) fn disassemble(chunk: &mut &[u8], constants: &[Gc<Value>]) {
+    /// All opcodes, in the order of their byte values.
+    pub(crate) const ALL: [OpCode; 32] = [
+        OpCode::Add,
+        OpCode::Call,
+        OpCode::Closure,
+        OpCode::Constant,
+        OpCode::DefineGlobal,
+        OpCode::Div,
+        OpCode::Equal,
+        OpCode::False,
+        OpCode::GetGlobal,
+        OpCode::GetLocal,
+        OpCode::Greater,
+        OpCode::GreaterEqual,
+        OpCode::Jump,
+        OpCode::JumpIfFalsePeek,
+        OpCode::JumpIfFalsePop,
+        OpCode::JumpIfTruePeek,
+        OpCode::Less,
+        OpCode::LessEqual,
+        OpCode::Loop,
+        OpCode::Mul,
+        OpCode::Neg,
+        OpCode::Nil,
+        OpCode::Not,
+        OpCode::Pop,
+        OpCode::Print,
+        OpCode::Return,
+        OpCode::SetGlobal,
+        OpCode::SetLocal,
+        OpCode::Sub,
+        OpCode::True,
+        OpCode::Echo,
+        OpCode::Wide,
+    ];
+
+    pub(crate) fn from_u8(byte: u8) -> Option<OpCode> {
+        OpCode::ALL.get(usize::from(byte)).copied()
+    }
+
+    pub(crate) fn from_name(name: &str) -> Option<OpCode> {
+        OpCode::ALL.iter().copied().find(|opcode| format!("{:?}", opcode) == name)
+    }
+
+    fn operand(self) -> Operand {
         use OpCode::*;
 
-        let instruction = unsafe { mem::transmute::<u8, OpCode>(chunk[0]) };
-        *chunk = &chunk[1..];
-        match instruction {
-            Add | Div | Equal | False | Greater | GreaterEqual | Less | LessEqual | Mul | Neg | Nil | Not | Pop | Print | Return | Sub | True => println!("{:?}", instruction),
-            Call | GetLocal | SetLocal => {
-                let arg = chunk[0];
-                *chunk = &chunk[1..];
-                println!("{:?} 0x{:02x}", instruction, arg);
-            }
-            Closure | Constant | DefineGlobal | GetGlobal | SetGlobal => {
-                let arg = chunk[0];
-                *chunk = &chunk[1..];
-                let constant = &constants[usize::from(arg)];
-                println!("{:?} 0x{:02x} ({})", instruction, arg, constant);
-            }
-            Jump | JumpIfFalsePeek | JumpIfFalsePop | JumpIfTruePeek | Loop => {
-                let offset = u16::from_le_bytes([chunk[0], chunk[1]]);
-                *chunk = &chunk[2..];
-                println!("{:?} 0x{:04x}", instruction, offset);
+        match self {
+            Add | Div | Echo | Equal | False | Greater | GreaterEqual | Less | LessEqual | Mul | Neg | Nil | Not | Pop | Print | Return | Sub | True | Wide => Operand::None,
+            Call => Operand::Byte,
+            Closure | Constant | DefineGlobal | GetGlobal | GetLocal | SetGlobal | SetLocal => Operand::Index,
+            Jump | JumpIfFalsePeek | JumpIfFalsePop | JumpIfTruePeek | Loop => Operand::Jump,
+        }
+    }
+
+    /// The length of this opcode's operand in bytes, or `None` if it can't have a `Wide` prefix.
+    fn operand_len(self, wide: bool) -> Option<usize> {
+        match (self.operand(), wide) {
+            (Operand::None, false) => Some(0),
+            (Operand::Byte, false) | (Operand::Index, false) => Some(1),
+            (Operand::Jump, false) => Some(2),
+            (Operand::Index, true) | (Operand::Jump, true) => Some(4),
+            (Operand::None, true) | (Operand::Byte, true) => None,
+        }
+    }
+
+    /// Whether this opcode's operand is a global slot index.
+    pub(crate) fn is_global(self) -> bool {
+        matches!(self, OpCode::DefineGlobal | OpCode::GetGlobal | OpCode::SetGlobal)
+    }
+
+    /// Appends the encoding of an instruction with this opcode to `chunk` and returns its length.
+    pub(crate) fn encode(self, operand: usize, wide: bool, chunk: &mut Vec<u8>) -> usize {
+        let operand_len = self.operand_len(wide).expect("opcode can't have a Wide prefix");
+        debug_assert!(operand >> (8 * operand_len) == 0, "operand doesn't fit");
+        if wide { chunk.push(OpCode::Wide as u8); }
+        chunk.push(self as u8);
+        chunk.extend_from_slice(&operand.to_le_bytes()[..operand_len]);
+        usize::from(wide) + 1 + operand_len
+    }
+
+    /// Decodes the instruction at `offset`, including its `Wide` prefix if any. Returns an error message if the chunk doesn't contain a valid instruction there.
+    pub(crate) fn decode(chunk: &[u8], offset: usize) -> Result<Instruction, String> {
+        let opcode_at = |offset: usize| OpCode::from_u8(chunk[offset]).ok_or_else(|| format!("invalid opcode 0x{:02x}", chunk[offset]));
+        let mut opcode = opcode_at(offset)?;
+        let wide = if let OpCode::Wide = opcode {
+            if offset + 1 >= chunk.len() { return Err(format!("Wide prefix at the end of the chunk")) }
+            opcode = opcode_at(offset + 1)?;
+            true
+        } else {
+            false
+        };
+        let operand_start = offset + if wide { 2 } else { 1 };
+        let operand_len = match opcode.operand_len(wide) {
+            Some(operand_len) => operand_len,
+            None => return Err(format!("{:?} can't have a Wide prefix", opcode)),
+        };
+        let operand = match chunk.get(operand_start..operand_start + operand_len) {
+            Some(bytes) => bytes.iter().rev().fold(0, |operand, &byte| operand << 8 | usize::from(byte)),
+            None => return Err(format!("{:?} instruction is missing operand bytes", opcode)),
+        };
+        Ok(Instruction { opcode, wide, operand, len: operand_start + operand_len - offset })
+    }
+
+    /// If the instruction at `offset` has a constant index operand, returns that index.
+    pub(crate) fn constant_operand(function: &FunctionInner, offset: usize) -> Option<usize> {
+        use OpCode::*;
+
+        let instruction = OpCode::decode(&function.chunk, offset).expect("invalid bytecode");
+        match instruction.opcode {
+            Closure | Constant => Some(instruction.operand),
+            _ => None,
+        }
+    }
+
+    /// Prints the instruction at `offset` as `offset line:column [Wide] OpCode operands` and returns the offset of the next instruction.
+    pub(crate) fn disassemble(function: &FunctionInner, globals: &[InternedString], offset: usize, out: &mut impl Write) -> io::Result<usize> {
+        use OpCode::*;
+
+        let FunctionInner { chunk, lines, constants, .. } = function;
+        write!(out, "{:04x} ", offset)?;
+        if offset > 0 && lines[offset] == lines[offset - 1] {
+            write!(out, "      | ")?;
+        } else {
+            write!(out, "{:>7} ", lines[offset].to_string())?;
+        }
+        let instruction = OpCode::decode(chunk, offset).expect("invalid bytecode");
+        if instruction.wide { write!(out, "Wide ")?; }
+        let Instruction { opcode, operand, .. } = instruction;
+        match opcode.operand() {
+            Operand::None => writeln!(out, "{:?}", opcode)?,
+            Operand::Byte => writeln!(out, "{:?} 0x{:02x}", opcode, operand)?,
+            Operand::Index => match opcode {
+                Closure | Constant => writeln!(out, "{:?} 0x{:02x} ({:?})", opcode, operand, constants[operand])?,
+                DefineGlobal | GetGlobal | SetGlobal => writeln!(out, "{:?} 0x{:02x} ({})", opcode, operand, globals[operand])?,
+                _ => writeln!(out, "{:?} 0x{:02x}", opcode, operand)?,
+            },
+            Operand::Jump => writeln!(out, "{:?} 0x{:04x} (-> {:04x})", opcode, operand, instruction.jump_target(offset).expect("jump target out of range"))?,
+        }
+        Ok(offset + instruction.len)
+    }
+}
+
+/// Assigns slot indices to the names of global variables. The compiler emits the slot indices, and the VM stores the values in a `Vec` indexed by them.
+#[derive(Default)]
+pub(crate) struct GlobalTable {
+    names: Vec<InternedString>,
+    slots: HashMap<InternedString, usize>,
+}
+
+impl GlobalTable {
+    /// Returns the slot of the global with the given name, assigning a new slot if it doesn't have one yet.
+    pub(crate) fn slot(&mut self, name: InternedString) -> usize {
+        if let Some(&slot) = self.slots.get(&name) { return slot }
+        let slot = self.names.len();
+        self.names.push(name.clone());
+        self.slots.insert(name, slot);
+        slot
+    }
+
+    pub(crate) fn get(&self, name: &InternedString) -> Option<usize> { self.slots.get(name).copied() }
+
+    pub(crate) fn names(&self) -> &[InternedString] { &self.names }
+
+    /// Makes bytecode which was compiled with a different global table, whose names are given, use the slots from this table instead.
+    pub(crate) fn link(&mut self, function: &mut FunctionInner, names: &[InternedString]) {
+        let slots = names.iter().map(|name| self.slot(name.clone())).collect::<Vec<_>>();
+        if slots.iter().enumerate().all(|(old_slot, &slot)| old_slot == slot) { return } // e.g. when running a script in a new VM
+        fn link_function(function: &mut FunctionInner, slots: &[usize]) {
+            function.reencode(|old_slot| slots[old_slot]);
+            for constant in &function.constants {
+                match *constant {
+                    Value::Closure(ref closure) => link_function(&mut closure.function.borrow_mut(), slots),
+                    Value::Function(ref function) => link_function(&mut function.borrow_mut(), slots),
+                    _ => {}
+                }
             }
         }
+        link_function(function, &slots)
     }
 }
 
@@ -108,200 +309,358 @@ pub struct CallFrame {
     slots_start: usize,
 }
 
+impl CallFrame {
+    /// The source position of the instruction currently being executed in this frame.
+    pub(crate) fn position(&self) -> Position {
+        self.closure.function.borrow().lines[self.ip - 1]
+    }
+}
+
 pub(crate) struct Vm {
     frames: Vec<CallFrame>,
-    stack: Vec<Gc<Value>>,
-    globals: HashMap<Gc<String>, Gc<Value>>,
+    stack: Vec<Value>,
+    /// The slots of global variables. `None` means the variable is undefined.
+    globals: Vec<Option<Value>>,
+    pub(crate) global_table: GlobalTable,
+    /// The table of all strings used by this VM, including those in its bytecode.
+    pub(crate) strings: Interner,
+    /// If set, allocating heap objects beyond this many bytes raises a runtime error.
+    pub(crate) heap_limit: Option<usize>,
+    /// Set from outside the VM (e.g. by a signal handler) to abort the current evaluation. Polled at backward jumps and calls.
+    interrupted: Arc<AtomicBool>,
+    /// If set, the value stack and each instruction are printed before the instruction is executed.
+    pub(crate) trace: bool,
+    /// If nonempty, only instructions in functions with these names are traced. Top-level code is called `<script>`.
+    pub(crate) trace_functions: Vec<String>,
 }
 
 impl Vm {
     pub(crate) fn new() -> Vm {
-        Vm {
+        let mut vm = Vm {
             frames: Vec::default(),
             stack: Vec::default(),
-            globals: crate::native::all(),
+            globals: Vec::default(),
+            global_table: GlobalTable::default(),
+            strings: Interner::default(),
+            heap_limit: None,
+            interrupted: Arc::default(),
+            trace: false,
+            trace_functions: Vec::default(),
+        };
+        vm.define_natives();
+        vm
+    }
+
+    fn define_natives(&mut self) {
+        for (name, value) in crate::native::all(&self.strings) {
+            let slot = self.global_table.slot(name);
+            if self.globals.len() <= slot { self.globals.resize(slot + 1, None) }
+            self.globals[slot] = Some(value);
         }
     }
 
+    /// Returns a flag which, when set, makes the VM abort the current evaluation with [`Error::Interrupted`].
+    pub(crate) fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        self.interrupted.clone()
+    }
+
+    /// Forgets an interrupt that arrived while no evaluation was running, e.g. after the last one finished.
+    pub(crate) fn clear_interrupt(&self) {
+        self.interrupted.store(false, SeqCst);
+    }
+
+    /// The names of all defined globals.
+    pub(crate) fn global_names(&self) -> impl Iterator<Item = &str> {
+        self.global_table.names().iter().zip(&self.globals).filter(|(_, value)| value.is_some()).map(|(name, _)| &**name)
+    }
+
+    pub(crate) fn global(&self, name: &str) -> Option<&Value> {
+        let slot = self.global_table.get(&self.strings.get(name)?)?;
+        self.globals.get(slot)?.as_ref()
+    }
+
+    /// Removes all globals except native functions.
+    pub(crate) fn reset(&mut self) {
+        self.stack.clear();
+        self.frames.clear();
+        self.globals.clear();
+        self.define_natives();
+    }
+
     pub(crate) fn interpret(&mut self, function: FunctionInner) -> Result {
+        self.globals.resize(self.global_table.names().len(), None); // the compiler may have assigned new slots
         let closure = Closure::new(function.wrap());
         self.push(Value::new(closure.clone()));
         self.call(closure, 0)?;
-        self.run()
+        // an interrupt that arrived while the input was being compiled cancels it
+        let result = self.check_interrupt().and_then(|()| self.run());
+        if result.is_err() {
+            // leave the VM in a usable state so the repl can keep going
+            self.stack.clear();
+            self.frames.clear();
+        }
+        result
     }
 
     fn run(&mut self) -> Result {
-        macro_rules! frame {
-            () => {
-                &mut self.frames.last_mut().expect("call frame stack empty")
-            };
-        }
+        'frames: loop {
+            // cache the current function's bytecode and instruction pointer, these are reloaded whenever the current call frame changes
+            let frame = self.frames.last().expect("call frame stack empty");
+            let closure = frame.closure.clone();
+            let slots_start = frame.slots_start;
+            let mut ip = frame.ip;
+            let function = closure.function.borrow();
+            let FunctionInner { chunk, constants, .. } = &*function;
+            let mut wide = false; // whether the current instruction has a `Wide` prefix
 
-        macro_rules! read_u8 {
-            () => {{
-                let frame = frame!();
-                let byte = frame.closure.function.borrow().chunk[frame.ip];
-                frame.ip += 1;
-                byte
-            }};
-        }
+            // stores the cached instruction pointer in the call frame, needed before anything that reads the call stack
+            macro_rules! sync_ip {
+                () => {
+                    self.frames.last_mut().expect("call frame stack empty").ip = ip;
+                };
+            }
 
-        macro_rules! read_u16 {
-            () => {
-                u16::from_le_bytes([read_u8!(), read_u8!()])
-            };
-        }
+            // shadow the macros of the same names so errors have an accurate call stack
+            macro_rules! error {
+                ($vm:expr, $($arg:tt)*) => {{
+                    sync_ip!();
+                    return Err(Error::Runtime {
+                        msg: format!($($arg)*),
+                        call_stack: $vm.frames.clone(),
+                    })
+                }};
+            }
 
-        macro_rules! read_constant {
-            () => {{
-                let const_idx = usize::from(read_u8!());
-                &frame!().closure.function.borrow().constants[const_idx]
-            }};
-        }
+            macro_rules! expect {
+                ($vm:expr, $option:expr, $($arg:tt)*) => {{
+                    match $option {
+                        Some(value) => value,
+                        None => error!($vm, $($arg)*),
+                    }
+                }};
+            }
 
-        loop {
-            let instruction = unsafe { mem::transmute::<u8, OpCode>(read_u8!()) };
-            match instruction {
-                OpCode::Add => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-                    self.push(match (&*lhs, &*rhs) {
-                        (Value::String(lhs), Value::String(rhs)) => Value::new(format!("{}{}", lhs, rhs)),
-                        (Value::Number(lhs), Value::Number(rhs)) => Value::new(lhs + rhs),
-                        (_, _) => error!(self, "Operands must be two numbers or two strings."),
-                    });
-                }
-                OpCode::Call => {
-                    let arg_count = read_u8!();
-                    let rcpt = self.peek(arg_count.into()).clone();
-                    self.call_value(rcpt, arg_count)?;
-                }
-                OpCode::Closure => {
-                    let function = read_constant!().as_function().expect("function constant was not a function");
-                    self.push(Value::new(Closure::new(function)));
-                    //TODO capture upvalues
-                }
-                OpCode::Constant => {
-                    let value = read_constant!().clone();
-                    self.push(value);
-                }
-                OpCode::DefineGlobal => {
-                    let name = read_constant!().as_string().expect("global name was not a string");
-                    let value = self.pop();
-                    self.globals.insert(name, value);
-                }
-                OpCode::Div => {
-                    let rhs = expect!(self, self.pop().as_number(), "Operands must be numbers.");
-                    let lhs = expect!(self, self.pop().as_number(), "Operands must be numbers.");
-                    self.push(Value::new(lhs / rhs));
-                }
-                OpCode::Equal => {
-                    let rhs = self.pop();
-                    let lhs = self.pop();
-                    self.push(Value::new(lhs == rhs));
-                }
-                OpCode::False => self.push(Value::new(false)),
-                OpCode::GetGlobal => {
-                    let name = read_constant!().as_string().expect("global name was not a string");
-                    let value = expect!(self, self.globals.get(&name), "Undefined variable '{}'.", name).clone();
-                    self.push(value);
-                }
-                OpCode::GetLocal => {
-                    let slot = read_u8!();
-                    let local = self.stack[frame!().slots_start + usize::from(slot)].clone();
-                    self.push(local);
-                }
-                OpCode::Greater => {
-                    let rhs = expect!(self, self.pop().as_number(), "Operands must be numbers.");
-                    let lhs = expect!(self, self.pop().as_number(), "Operands must be numbers.");
-                    self.push(Value::new(lhs > rhs));
-                }
-                OpCode::GreaterEqual => {
-                    let rhs = expect!(self, self.pop().as_number(), "Operands must be numbers.");
-                    let lhs = expect!(self, self.pop().as_number(), "Operands must be numbers.");
-                    self.push(Value::new(lhs >= rhs));
-                }
-                OpCode::Jump => {
-                    let offset = read_u16!();
-                    frame!().ip += usize::from(offset);
-                }
-                OpCode::JumpIfFalsePeek => {
-                    let offset = read_u16!();
-                    if !self.peek(0).as_bool() { frame!().ip += usize::from(offset) }
-                }
-                OpCode::JumpIfFalsePop => {
-                    let offset = read_u16!();
-                    if !self.pop().as_bool() { frame!().ip += usize::from(offset) }
-                }
-                OpCode::JumpIfTruePeek => {
-                    let offset = read_u16!();
-                    if self.peek(0).as_bool() { frame!().ip += usize::from(offset) }
-                }
-                OpCode::Less => {
-                    let rhs = expect!(self, self.pop().as_number(), "Operands must be numbers.");
-                    let lhs = expect!(self, self.pop().as_number(), "Operands must be numbers.");
-                    self.push(Value::new(lhs < rhs));
-                }
-                OpCode::LessEqual => {
-                    let rhs = expect!(self, self.pop().as_number(), "Operands must be numbers.");
-                    let lhs = expect!(self, self.pop().as_number(), "Operands must be numbers.");
-                    self.push(Value::new(lhs <= rhs));
-                }
-                OpCode::Loop => {
-                    let offset = read_u16!();
-                    frame!().ip -= usize::from(offset);
-                }
-                OpCode::Mul => {
-                    let rhs = expect!(self, self.pop().as_number(), "Operands must be numbers.");
-                    let lhs = expect!(self, self.pop().as_number(), "Operands must be numbers.");
-                    self.push(Value::new(lhs * rhs));
-                }
-                OpCode::Neg => {
-                    let n = expect!(self, self.pop().as_number(), "Operand must be a number.");
-                    self.push(Value::new(-n));
-                }
-                OpCode::Nil => self.push(Value::nil()),
-                OpCode::Not => {
-                    let operand = self.pop();
-                    self.push(Value::new(!operand.as_bool()));
+            macro_rules! read_u8 {
+                () => {{
+                    let byte = chunk[ip];
+                    ip += 1;
+                    byte
+                }};
+            }
+
+            macro_rules! read_u32 {
+                () => {
+                    u32::from_le_bytes([read_u8!(), read_u8!(), read_u8!(), read_u8!()]) as usize
+                };
+            }
+
+            // index operands are 1 byte and jump offsets 2 bytes long, both are 4 bytes long after a `Wide` prefix
+            macro_rules! read_index {
+                () => {
+                    if wide { read_u32!() } else { usize::from(read_u8!()) }
+                };
+            }
+
+            macro_rules! read_jump {
+                () => {
+                    if wide { read_u32!() } else { usize::from(u16::from_le_bytes([read_u8!(), read_u8!()])) }
+                };
+            }
+
+            macro_rules! read_constant {
+                () => {
+                    &constants[read_index!()]
+                };
+            }
+
+            loop {
+                if self.trace && !wide {
+                    sync_ip!();
+                    self.trace_instruction();
                 }
-                OpCode::Pop => { let _ = self.pop(); }
-                OpCode::Print => println!("{}", self.pop()),
-                OpCode::Return => {
-                    let result = self.pop();
-                    //TODO close upvalues
-                    let popped_frame = self.frames.pop().expect("tried to return from empty call stack");
-                    if self.frames.is_empty() {
-                        let _ = self.pop();
-                        return Ok(())
+                let instruction = unsafe { mem::transmute::<u8, OpCode>(read_u8!()) }; //SAFETY: bytecode is either generated by the compiler or checked by the verifier when loaded
+                match instruction {
+                    OpCode::Add => {
+                        let rhs = self.pop();
+                        let lhs = self.pop();
+                        let sum = match (&lhs, &rhs) {
+                            (Value::String(lhs), Value::String(rhs)) => {
+                                sync_ip!();
+                                self.reserve(Value::string_size(lhs.len() + rhs.len()))?;
+                                Value::String(self.strings.intern(format!("{}{}", lhs, rhs)))
+                            }
+                            (Value::Number(lhs), Value::Number(rhs)) => Value::new(lhs + rhs),
+                            (_, _) => error!(self, "Operands must be two numbers or two strings."),
+                        };
+                        self.push(sum);
                     }
-                    self.stack.truncate(popped_frame.slots_start);
-                    self.push(result);
-                }
-                OpCode::SetGlobal => {
-                    let name = read_constant!().as_string().expect("global name was not a string");
-                    let value = self.peek(0).clone();
-                    if self.globals.insert(name.clone(), value).is_none() {
-                        self.globals.remove(&name);
-                        error!(self, "Undefined variable '{}'.", name)
+                    OpCode::Call => {
+                        let arg_count = read_u8!();
+                        let rcpt = self.peek(arg_count.into()).clone();
+                        sync_ip!();
+                        self.call_value(rcpt, arg_count)?;
+                        continue 'frames
+                    }
+                    OpCode::Closure => {
+                        let function = read_constant!().as_function().expect("function constant was not a function");
+                        sync_ip!();
+                        self.reserve(Closure::SIZE)?;
+                        self.push(Value::new(Closure::new(function)));
+                        //TODO capture upvalues
+                    }
+                    OpCode::Constant => {
+                        let value = read_constant!().clone();
+                        self.push(value);
+                    }
+                    OpCode::DefineGlobal => {
+                        let slot = read_index!();
+                        let value = self.pop();
+                        self.globals[slot] = Some(value);
+                    }
+                    OpCode::Div => {
+                        let rhs = expect!(self, self.pop().as_number(), "Operands must be numbers.");
+                        let lhs = expect!(self, self.pop().as_number(), "Operands must be numbers.");
+                        self.push(Value::new(lhs / rhs));
+                    }
+                    OpCode::Echo => {
+                        let value = self.pop();
+                        if let Value::Nil = value {} else { println!("{}", value) }
+                    }
+                    OpCode::Equal => {
+                        let rhs = self.pop();
+                        let lhs = self.pop();
+                        self.push(Value::new(lhs == rhs));
+                    }
+                    OpCode::False => self.push(Value::new(false)),
+                    OpCode::GetGlobal => {
+                        let slot = read_index!();
+                        let value = expect!(self, self.globals[slot].as_ref(), "Undefined variable '{}'.", self.global_table.names()[slot]).clone();
+                        self.push(value);
+                    }
+                    OpCode::GetLocal => {
+                        let slot = read_index!();
+                        let local = self.stack[slots_start + slot].clone();
+                        self.push(local);
+                    }
+                    OpCode::Greater => {
+                        let rhs = expect!(self, self.pop().as_number(), "Operands must be numbers.");
+                        let lhs = expect!(self, self.pop().as_number(), "Operands must be numbers.");
+                        self.push(Value::new(lhs > rhs));
+                    }
+                    OpCode::GreaterEqual => {
+                        let rhs = expect!(self, self.pop().as_number(), "Operands must be numbers.");
+                        let lhs = expect!(self, self.pop().as_number(), "Operands must be numbers.");
+                        self.push(Value::new(lhs >= rhs));
+                    }
+                    OpCode::Jump => {
+                        let offset = read_jump!();
+                        ip += offset;
+                    }
+                    OpCode::JumpIfFalsePeek => {
+                        let offset = read_jump!();
+                        if !self.peek(0).as_bool() { ip += offset }
+                    }
+                    OpCode::JumpIfFalsePop => {
+                        let offset = read_jump!();
+                        if !self.pop().as_bool() { ip += offset }
+                    }
+                    OpCode::JumpIfTruePeek => {
+                        let offset = read_jump!();
+                        if self.peek(0).as_bool() { ip += offset }
+                    }
+                    OpCode::Less => {
+                        let rhs = expect!(self, self.pop().as_number(), "Operands must be numbers.");
+                        let lhs = expect!(self, self.pop().as_number(), "Operands must be numbers.");
+                        self.push(Value::new(lhs < rhs));
+                    }
+                    OpCode::LessEqual => {
+                        let rhs = expect!(self, self.pop().as_number(), "Operands must be numbers.");
+                        let lhs = expect!(self, self.pop().as_number(), "Operands must be numbers.");
+                        self.push(Value::new(lhs <= rhs));
+                    }
+                    OpCode::Loop => {
+                        let offset = read_jump!();
+                        sync_ip!();
+                        self.check_interrupt()?;
+                        ip -= offset;
+                    }
+                    OpCode::Mul => {
+                        let rhs = expect!(self, self.pop().as_number(), "Operands must be numbers.");
+                        let lhs = expect!(self, self.pop().as_number(), "Operands must be numbers.");
+                        self.push(Value::new(lhs * rhs));
+                    }
+                    OpCode::Neg => {
+                        let n = expect!(self, self.pop().as_number(), "Operand must be a number.");
+                        self.push(Value::new(-n));
+                    }
+                    OpCode::Nil => self.push(Value::nil()),
+                    OpCode::Not => {
+                        let operand = self.pop();
+                        self.push(Value::new(!operand.as_bool()));
+                    }
+                    OpCode::Pop => { let _ = self.pop(); }
+                    OpCode::Print => println!("{}", self.pop()),
+                    OpCode::Return => {
+                        let result = self.pop();
+                        //TODO close upvalues
+                        let popped_frame = self.frames.pop().expect("tried to return from empty call stack");
+                        if self.frames.is_empty() {
+                            let _ = self.pop();
+                            return Ok(())
+                        }
+                        self.stack.truncate(popped_frame.slots_start);
+                        self.push(result);
+                        continue 'frames
+                    }
+                    OpCode::SetGlobal => {
+                        let slot = read_index!();
+                        let value = self.peek(0).clone();
+                        if let Some(global) = &mut self.globals[slot] {
+                            *global = value;
+                        } else {
+                            let name = self.global_table.names()[slot].clone();
+                            error!(self, "Undefined variable '{}'.", name)
+                        }
+                    }
+                    OpCode::SetLocal => {
+                        let slot = read_index!();
+                        self.stack[slots_start + slot] = self.peek(0).clone();
+                    }
+                    OpCode::Sub => {
+                        let rhs = expect!(self, self.pop().as_number(), "Operands must be numbers.");
+                        let lhs = expect!(self, self.pop().as_number(), "Operands must be numbers.");
+                        self.push(Value::new(lhs - rhs));
+                    }
+                    OpCode::True => self.push(Value::new(true)),
+                    OpCode::Wide => {
+                        wide = true;
+                        continue
                     }
                 }
-                OpCode::SetLocal => {
-                    let slot = read_u8!();
-                    self.stack[frame!().slots_start + usize::from(slot)] = self.peek(0).clone();
-                }
-                OpCode::Sub => {
-                    let rhs = expect!(self, self.pop().as_number(), "Operands must be numbers.");
-                    let lhs = expect!(self, self.pop().as_number(), "Operands must be numbers.");
-                    self.push(Value::new(lhs - rhs));
-                }
-                OpCode::True => self.push(Value::new(true)),
+                wide = false;
             }
         }
     }
 
-    fn call_value(&mut self, value: Gc<Value>, arg_count: u8) -> Result {
-        match *value {
+    fn trace_instruction(&self) {
+        let frame = self.frames.last().expect("call frame stack empty");
+        let function = frame.closure.function.borrow();
+        if !self.trace_functions.is_empty() {
+            let name = function.to_string();
+            if !self.trace_functions.iter().any(|trace_name| *trace_name == name) { return }
+        }
+        print!("     ");
+        for value in &self.stack {
+            print!("[ {} ]", value);
+        }
+        println!();
+        print!("{:>2} | ", self.frames.len());
+        OpCode::disassemble(&function, self.global_table.names(), frame.ip, &mut io::stdout().lock()).expect("failed to print trace");
+    }
+
+    fn call_value(&mut self, value: Value, arg_count: u8) -> Result {
+        self.check_interrupt()?;
+        match value {
             Value::Closure(ref closure) => self.call(closure.clone(), arg_count),
             Value::NativeFn(crate::value::NativeFn { inner }) => {
                 let result = inner(&self.stack[self.stack.len() - usize::from(arg_count)..self.stack.len()]);
@@ -326,15 +685,35 @@ impl Vm {
         Ok(())
     }
 
-    fn push(&mut self, value: Gc<Value>) {
+    fn check_interrupt(&self) -> Result {
+        if self.interrupted.swap(false, SeqCst) {
+            return Err(Error::Interrupted {
+                call_stack: self.frames.clone(),
+            })
+        }
+        Ok(())
+    }
+
+    /// Checks that `size` more bytes can be allocated without exceeding the heap limit, collecting garbage if necessary.
+    fn reserve(&self, size: usize) -> Result {
+        if let Some(limit) = self.heap_limit {
+            if heap::bytes_allocated() + size > limit {
+                gc::force_collect();
+                if heap::bytes_allocated() + size > limit { error!(self, "Out of memory.") }
+            }
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, value: Value) {
         self.stack.push(value);
     }
 
-    fn peek(&self, offset: usize) -> &Gc<Value> {
+    fn peek(&self, offset: usize) -> &Value {
         &self.stack[self.stack.len() - 1 - offset]
     }
 
-    fn pop(&mut self) -> Gc<Value> {
+    fn pop(&mut self) -> Value {
         self.stack.pop().expect("tried to pop from an empty VM stack")
     }
 }